@@ -1,26 +1,132 @@
+pub mod acl;
+pub mod buckets;
+pub mod cat;
+pub mod cors;
 pub mod cp;
-mod util;
+mod encryption;
+mod endpoint;
+pub mod iam;
+pub mod lifecycle;
+pub mod ls;
+pub mod mb;
+pub(crate) mod progress;
+pub mod rb;
+pub(crate) mod resumable;
+pub mod retention;
+pub(crate) mod retry;
+pub mod rm;
+pub mod rsync;
+pub mod setmeta;
+pub mod signurl;
+pub mod stat;
+pub(crate) mod throttle;
+pub(crate) mod util;
+mod wildcard;
+
+pub use endpoint::Endpoint;
+
+/// Options for every command that sends requests to GCS
+#[derive(clap::Parser)]
+pub struct Options {
+    /// The endpoint of a GCS emulator, eg. `http://localhost:4443`, that the
+    /// `gcs` and `syms` commands send requests to instead of GCS. Requests to
+    /// the emulator are not authenticated
+    #[clap(long, global = true, env = "STORAGE_EMULATOR_HOST")]
+    pub gcs_endpoint: Option<Endpoint>,
+    /// The maximum number of times a GCS request is attempted. Requests that
+    /// failed due to eg. rate limiting or transient server errors are retried
+    /// with an exponential backoff, if they are idempotent
+    #[clap(long, global = true, default_value = "5", value_parser = clap::value_parser!(u32).range(1..))]
+    pub gcs_max_attempts: u32,
+    /// Limits the combined bandwidth of all uploads and downloads, eg.
+    /// `50MiB/s`, so that large transfers don't saturate a shared link
+    #[clap(long, global = true, value_parser = throttle::parse_bandwidth)]
+    pub max_bandwidth: Option<u64>,
+}
 
 /// Performs GCS operations
+#[derive(clap::Parser)]
+pub struct Args {
+    #[clap(flatten)]
+    pub opts: Options,
+    #[clap(subcommand)]
+    pub cmd: Command,
+}
+
 #[derive(clap::Subcommand)]
-pub enum Args {
+pub enum Command {
+    #[clap(subcommand)]
+    Acl(acl::Args),
+    #[clap(subcommand)]
+    Buckets(buckets::Args),
+    Cat(cat::Args),
+    #[clap(subcommand)]
+    Cors(cors::Args),
     Cp(cp::Args),
+    #[clap(subcommand)]
+    Iam(iam::Args),
+    #[clap(subcommand)]
+    Lifecycle(lifecycle::Args),
+    Ls(ls::Args),
+    Mb(mb::Args),
+    Rb(rb::Args),
+    #[clap(subcommand)]
+    Retention(retention::Args),
+    Rm(rm::Args),
+    Rsync(rsync::Args),
+    Setmeta(setmeta::Args),
+    Signurl(signurl::Args),
+    Stat(stat::Args),
 }
 
 impl crate::Scopes for Args {
     fn scopes(&self) -> &'static [&'static str] {
-        &["https://www.googleapis.com/auth/devstorage.full_control"]
+        // Emulators don't authenticate requests, and the user might not even
+        // have credentials, eg. in CI
+        if self.opts.gcs_endpoint.is_some() {
+            return &[];
+        }
+
+        match self.cmd {
+            // URLs are signed locally with the service account's key
+            Command::Signurl(_) => &[],
+            _ => &["https://www.googleapis.com/auth/devstorage.full_control"],
+        }
     }
 }
 
 pub async fn run(args: Args, client: reqwest::ClientBuilder) -> anyhow::Result<()> {
+    let Args { opts, cmd } = args;
+
     let rctx = util::RequestContext {
         client: client.build()?,
-        obj: tame_gcs::objects::Object::default(),
+        obj: opts
+            .gcs_endpoint
+            .as_ref()
+            .map_or_else(tame_gcs::objects::Object::default, Endpoint::object),
+        endpoint: opts.gcs_endpoint,
+        retry: retry::Policy::new(opts.gcs_max_attempts),
+        progress: progress::Progress::new(),
+        throttle: throttle::Throttle::new(opts.max_bandwidth),
     };
 
-    match args {
-        Args::Cp(cp) => cp::run(&rctx, cp).await?,
+    match cmd {
+        Command::Acl(acl) => acl::run(&rctx, acl).await?,
+        Command::Buckets(buckets) => buckets::run(&rctx, buckets).await?,
+        Command::Cat(cat) => cat::run(&rctx, cat).await?,
+        Command::Cors(cors) => cors::run(&rctx, cors).await?,
+        Command::Cp(cp) => cp::run(&rctx, cp).await?,
+        Command::Iam(iam) => iam::run(&rctx, iam).await?,
+        Command::Lifecycle(lifecycle) => lifecycle::run(&rctx, lifecycle).await?,
+        Command::Ls(ls) => ls::run(&rctx, ls).await?,
+        Command::Mb(mb) => mb::run(&rctx, mb).await?,
+        Command::Rb(rb) => rb::run(&rctx, rb).await?,
+        Command::Retention(retention) => retention::run(&rctx, retention).await?,
+        Command::Rm(rm) => rm::run(&rctx, rm).await?,
+        Command::Rsync(rsync) => rsync::run(&rctx, rsync).await?,
+        Command::Setmeta(setmeta) => setmeta::run(&rctx, setmeta).await?,
+        Command::Signurl(signurl) => signurl::run(signurl)?,
+        Command::Stat(stat) => stat::run(&rctx, stat).await?,
     }
 
     Ok(())
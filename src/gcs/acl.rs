@@ -0,0 +1,262 @@
+use super::util;
+use anyhow::Context as _;
+use serde_json::{Map, Value};
+use tame_gcs::{common::Projection, objects, ObjectName};
+
+/// Gets or modifies the ACL of an object
+#[derive(clap::Subcommand)]
+pub enum Args {
+    /// Prints the ACL of an object as JSON
+    Get {
+        /// The gs: URL of the object, a specific generation of the object can
+        /// be retrieved via `gs://bucket/object#<generation>`
+        url: String,
+    },
+    /// Grants entities access to, or removes entities from, the ACL of an
+    /// object
+    Ch(ChArgs),
+}
+
+#[derive(clap::Parser)]
+pub struct ChArgs {
+    /// Removes an entity from the ACL, eg. `-d allUsers`
+    #[clap(short = 'd', long = "delete")]
+    delete: Vec<String>,
+    /// The gs: URL of the object, a specific generation of the object can be
+    /// changed via `gs://bucket/object#<generation>`
+    url: String,
+    /// One or more `<entity>:<role>` grants, eg. `user-x@y.com:READER`,
+    /// `group-g@y.com:OWNER` or `allUsers:R`. The role is one of `READER`/`R`
+    /// or `OWNER`/`O`, and replaces the entity's current role
+    grants: Vec<String>,
+}
+
+/// The number of times a read-modify-write of an ACL is attempted before
+/// giving up due to concurrent modifications
+const MAX_ATTEMPTS: usize = 5;
+
+/// Retrieves the resource of the object, or the specified generation of it,
+/// including its ACL
+async fn get_object(
+    ctx: &util::RequestContext,
+    gs_url: &util::GsUrl,
+    object: &ObjectName<'_>,
+) -> anyhow::Result<Map<String, Value>> {
+    let get_req = ctx.obj.get(
+        &(gs_url.bucket(), object),
+        Some(objects::GetObjectOptional {
+            projection: Some(Projection::Full),
+            generation: gs_url.generation(),
+            ..Default::default()
+        }),
+    )?;
+
+    util::execute_json(ctx, get_req)
+        .await
+        .with_context(|| format!("failed to retrieve '{}'", display_url(gs_url, object)))
+}
+
+/// Formats the url of the object, including its generation if one was specified
+fn display_url(gs_url: &util::GsUrl, object: &ObjectName<'_>) -> String {
+    match gs_url.generation() {
+        Some(gen) => format!("gs://{}/{object}#{gen}", gs_url.bucket()),
+        None => format!("gs://{}/{object}", gs_url.bucket()),
+    }
+}
+
+fn parse_grant(grant: &str) -> anyhow::Result<(&str, &'static str)> {
+    let (entity, role) = grant
+        .rsplit_once(':')
+        .with_context(|| format!("'{grant}' is not of the form <entity>:<role>"))?;
+
+    let role = match role.to_ascii_uppercase().as_str() {
+        "R" | "READ" | "READER" => "READER",
+        "O" | "OWNER" | "FULL_CONTROL" => "OWNER",
+        _ => anyhow::bail!("'{role}' is not a valid object role, expected READER or OWNER"),
+    };
+
+    anyhow::ensure!(!entity.is_empty(), "'{grant}' does not specify an entity");
+
+    Ok((entity, role))
+}
+
+/// Applies the changes to the ACL, stripping each entry down to its entity and
+/// role, as the other fields are read-only
+fn apply_changes(acl: &[Value], add: &[(&str, &str)], remove: &[String]) -> Vec<Value> {
+    let mut entries: Vec<(String, String)> = acl
+        .iter()
+        .filter_map(|entry| {
+            Some((
+                entry.get("entity")?.as_str()?.to_owned(),
+                entry.get("role")?.as_str()?.to_owned(),
+            ))
+        })
+        .collect();
+
+    entries.retain(|(entity, _)| !remove.contains(entity));
+
+    for (entity, role) in add {
+        match entries.iter_mut().find(|(e, _)| e == entity) {
+            Some(entry) => entry.1 = (*role).to_owned(),
+            None => entries.push(((*entity).to_owned(), (*role).to_owned())),
+        }
+    }
+
+    entries
+        .into_iter()
+        .map(|(entity, role)| serde_json::json!({ "entity": entity, "role": role }))
+        .collect()
+}
+
+async fn ch(ctx: &util::RequestContext, args: ChArgs) -> anyhow::Result<()> {
+    let parsed = url::Url::parse(&args.url)?;
+    let gs_url = util::gs_url_to_object_id(&parsed)?;
+    let object = gs_url
+        .object()
+        .with_context(|| format!("'{}' is not an object", args.url))?;
+    let url = display_url(&gs_url, object);
+
+    let add = args
+        .grants
+        .iter()
+        .map(|grant| parse_grant(grant))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+
+    anyhow::ensure!(
+        !add.is_empty() || !args.delete.is_empty(),
+        "no ACL changes were specified"
+    );
+
+    // The ACL is only replaced if the object's metageneration hasn't changed
+    // since we read it, otherwise we start over with the new ACL rather than
+    // clobbering another change
+    for _ in 0..MAX_ATTEMPTS {
+        let resource = get_object(ctx, &gs_url, object).await?;
+        let metageneration = resource
+            .get("metageneration")
+            .and_then(|mg| mg.as_str())
+            .and_then(|mg| mg.parse().ok())
+            .context("object resource did not contain a metageneration")?;
+
+        let acl = match resource.get("acl") {
+            Some(Value::Array(acl)) => acl.as_slice(),
+            _ => &[],
+        };
+        let acl = apply_changes(acl, &add, &args.delete);
+
+        let patch_req = ctx.obj.patch(
+            &(gs_url.bucket(), object),
+            &objects::Metadata::default(),
+            Some(objects::PatchObjectOptional {
+                conditionals: util::Preconditions {
+                    if_metageneration_match: Some(metageneration),
+                    ..Default::default()
+                }
+                .conditionals(),
+                ..Default::default()
+            }),
+        )?;
+        let patch_req = util::with_generation(patch_req, gs_url.generation())?;
+        let patch_req = util::with_json_body(patch_req, &serde_json::json!({ "acl": acl }))?;
+
+        match util::execute_json(ctx, patch_req).await {
+            Ok(_) => {
+                println!("Updated ACL for {url}");
+                return Ok(());
+            }
+            Err(err) if util::is_precondition_failure(&err) => continue,
+            Err(err) => return Err(err).with_context(|| format!("failed to set ACL for '{url}'")),
+        }
+    }
+
+    anyhow::bail!(
+        "failed to update ACL for '{url}', it was concurrently modified {MAX_ATTEMPTS} times"
+    )
+}
+
+pub async fn run(ctx: &util::RequestContext, args: Args) -> anyhow::Result<()> {
+    match args {
+        Args::Get { url } => {
+            let parsed = url::Url::parse(&url)?;
+            let gs_url = util::gs_url_to_object_id(&parsed)?;
+            let object = gs_url
+                .object()
+                .with_context(|| format!("'{url}' is not an object"))?;
+
+            let resource = get_object(ctx, &gs_url, object).await?;
+            let acl = resource
+                .get("acl")
+                .cloned()
+                .unwrap_or_else(|| Value::Array(Vec::new()));
+            println!("{}", serde_json::to_string_pretty(&acl)?);
+        }
+        Args::Ch(args) => ch(ctx, args).await?,
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn acl() -> Vec<Value> {
+        vec![
+            json!({ "entity": "user-a@y.com", "role": "OWNER", "etag": "CAE=", "kind": "storage#objectAccessControl" }),
+            json!({ "entity": "allUsers", "role": "READER" }),
+        ]
+    }
+
+    #[test]
+    fn parses_grants() {
+        assert_eq!(parse_grant("allUsers:R").unwrap(), ("allUsers", "READER"));
+        assert_eq!(
+            parse_grant("user-x@y.com:full_control").unwrap(),
+            ("user-x@y.com", "OWNER")
+        );
+        assert!(parse_grant("allUsers").is_err());
+        assert!(parse_grant(":READER").is_err());
+        assert!(parse_grant("allUsers:WRITER").is_err());
+    }
+
+    #[test]
+    fn adds_entities() {
+        assert_eq!(
+            apply_changes(
+                &acl(),
+                &[("group-g@y.com", "READER"), ("allUsers", "OWNER")],
+                &[]
+            ),
+            vec![
+                json!({ "entity": "user-a@y.com", "role": "OWNER" }),
+                json!({ "entity": "allUsers", "role": "OWNER" }),
+                json!({ "entity": "group-g@y.com", "role": "READER" }),
+            ]
+        );
+
+        // Granting the same role twice doesn't duplicate the entry
+        assert_eq!(
+            apply_changes(
+                &acl(),
+                &[("allUsers", "READER"), ("allUsers", "READER")],
+                &[]
+            ),
+            vec![
+                json!({ "entity": "user-a@y.com", "role": "OWNER" }),
+                json!({ "entity": "allUsers", "role": "READER" }),
+            ]
+        );
+    }
+
+    #[test]
+    fn removes_entities() {
+        let acl = apply_changes(&acl(), &[], &["allUsers".to_owned()]);
+        assert_eq!(
+            acl,
+            vec![json!({ "entity": "user-a@y.com", "role": "OWNER" })]
+        );
+
+        assert!(apply_changes(&acl, &[], &["user-a@y.com".to_owned()]).is_empty());
+    }
+}
@@ -0,0 +1,169 @@
+use super::util;
+use anyhow::Context as _;
+use serde_json::Value;
+use tame_gcs::http;
+
+/// Performs operations on the buckets of a project
+#[derive(clap::Subcommand)]
+pub enum Args {
+    Ls(LsArgs),
+}
+
+/// Lists the buckets in a project
+#[derive(clap::Parser)]
+pub struct LsArgs {
+    /// The project whose buckets are listed
+    #[clap(short = 'p', long, env = "GOOGLE_CLOUD_PROJECT")]
+    project: String,
+    /// Prints the location, default storage class and creation time of each
+    /// bucket
+    #[clap(short = 'l', long)]
+    long: bool,
+    /// Prints the full bucket resources as returned by GCS
+    #[clap(long, conflicts_with = "long")]
+    json: bool,
+}
+
+/// Retrieves the fields of a bucket's resource
+pub(super) async fn get(
+    ctx: &util::RequestContext,
+    bucket: &tame_gcs::BucketName<'_>,
+    fields: &str,
+) -> anyhow::Result<serde_json::Map<String, Value>> {
+    let get_req = http::Request::builder()
+        .method(http::Method::GET)
+        .uri(format!(
+            "{}?{}",
+            util::bucket_url(ctx, Some(bucket))?,
+            url::form_urlencoded::Serializer::new(String::new())
+                .append_pair("fields", fields)
+                .finish()
+        ))
+        .body(std::io::empty())?;
+
+    util::execute_json(ctx, get_req)
+        .await
+        .with_context(|| format!("failed to retrieve bucket 'gs://{bucket}'"))
+}
+
+/// Updates the fields of a bucket's resource that are present in the patch,
+/// where null values remove the field
+pub(super) async fn patch(
+    ctx: &util::RequestContext,
+    bucket: &tame_gcs::BucketName<'_>,
+    patch: &Value,
+) -> anyhow::Result<()> {
+    let patch_req = http::Request::builder()
+        .method(http::Method::PATCH)
+        .uri(util::bucket_url(ctx, Some(bucket))?)
+        .body(std::io::empty())?;
+    let patch_req = util::with_json_body(patch_req, patch)?;
+
+    util::execute_json(ctx, patch_req)
+        .await
+        .with_context(|| format!("failed to update bucket 'gs://{bucket}'"))?;
+
+    Ok(())
+}
+
+/// Reads and validates a JSON configuration from a file, or stdin if the path
+/// is `-`
+pub(super) fn read_config<T: serde::de::DeserializeOwned>(path: &str) -> anyhow::Result<T> {
+    let json = if path == "-" {
+        let mut json = Vec::new();
+        std::io::Read::read_to_end(&mut std::io::stdin(), &mut json)
+            .context("failed to read stdin")?;
+        json
+    } else {
+        std::fs::read(path).with_context(|| format!("failed to read '{path}'"))?
+    };
+
+    serde_json::from_slice(&json).with_context(|| format!("'{path}' is not a valid configuration"))
+}
+
+/// Lists every bucket in the project, paging through the results until the
+/// listing is exhausted
+async fn list(
+    ctx: &util::RequestContext,
+    project: &str,
+) -> anyhow::Result<Vec<serde_json::Map<String, Value>>> {
+    let mut buckets = Vec::new();
+    let mut page_token: Option<String> = None;
+
+    loop {
+        let query = {
+            let mut ser = url::form_urlencoded::Serializer::new(String::new());
+            ser.append_pair("project", project);
+            if let Some(pt) = &page_token {
+                ser.append_pair("pageToken", pt);
+            }
+            ser.finish()
+        };
+
+        let list_req = http::Request::builder()
+            .method(http::Method::GET)
+            .uri(format!("{}?{query}", util::bucket_url(ctx, None)?))
+            .body(std::io::empty())?;
+
+        let mut res = util::execute_json(ctx, list_req).await?;
+
+        if let Some(Value::Array(items)) = res.remove("items") {
+            buckets.extend(items.into_iter().filter_map(|item| match item {
+                Value::Object(bucket) => Some(bucket),
+                _ => None,
+            }));
+        }
+
+        page_token = match res.remove("nextPageToken") {
+            Some(Value::String(pt)) => Some(pt),
+            _ => None,
+        };
+        if page_token.is_none() {
+            break;
+        }
+    }
+
+    Ok(buckets)
+}
+
+async fn ls(ctx: &util::RequestContext, args: LsArgs) -> anyhow::Result<()> {
+    let buckets = list(ctx, &args.project)
+        .await
+        .with_context(|| format!("failed to list buckets in project '{}'", args.project))?;
+
+    if args.json {
+        println!("{}", serde_json::to_string_pretty(&buckets)?);
+        return Ok(());
+    }
+
+    let field = |bucket: &serde_json::Map<String, Value>, key: &str| {
+        bucket
+            .get(key)
+            .and_then(|v| v.as_str())
+            .unwrap_or_default()
+            .to_owned()
+    };
+
+    for bucket in &buckets {
+        let name = field(bucket, "name");
+
+        if args.long {
+            println!(
+                "{:<16}{:<10}{:<32}gs://{name}",
+                field(bucket, "location"),
+                field(bucket, "storageClass"),
+                field(bucket, "timeCreated"),
+            );
+        } else {
+            println!("gs://{name}");
+        }
+    }
+
+    Ok(())
+}
+
+pub async fn run(ctx: &util::RequestContext, args: Args) -> anyhow::Result<()> {
+    match args {
+        Args::Ls(args) => ls(ctx, args).await,
+    }
+}
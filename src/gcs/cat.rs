@@ -0,0 +1,90 @@
+use super::{cp, encryption, util, wildcard};
+use anyhow::Context as _;
+use tame_gcs::{objects, BucketName, ObjectName};
+
+/// Writes the contents of one or more objects to stdout
+#[derive(clap::Parser)]
+pub struct Args {
+    /// A base64 encoded AES-256 customer-supplied encryption key (CSEK) that
+    /// the objects are encrypted with
+    #[clap(long)]
+    decryption_key: Option<encryption::Key>,
+    /// One or more gs: URLs of the objects to write, in order. Supports `*`,
+    /// `**` and `?` wildcards, in which case each match is written, and
+    /// `gs://bucket/object#<generation>` to write a specific generation
+    #[clap(required = true)]
+    urls: Vec<String>,
+}
+
+/// Streams a single object to stdout
+async fn cat(
+    ctx: &util::RequestContext,
+    bucket: &BucketName<'_>,
+    object: &ObjectName<'_>,
+    generation: Option<i64>,
+    key: Option<&encryption::Key>,
+) -> anyhow::Result<()> {
+    let dl_req = encryption::with_key(
+        ctx.obj.download(
+            &(bucket, object),
+            Some(objects::DownloadObjectOptional {
+                generation,
+                ..Default::default()
+            }),
+        )?,
+        key,
+    );
+    let response = util::execute_stream(ctx, dl_req).await?;
+
+    cp::write_response(
+        ctx,
+        &format!("gs://{bucket}/{object}"),
+        response,
+        std::io::stdout(),
+        None,
+    )
+    .await
+    .with_context(|| format!("failed to write 'gs://{bucket}/{object}' to stdout"))
+}
+
+pub async fn run(ctx: &util::RequestContext, args: Args) -> anyhow::Result<()> {
+    for url in &args.urls {
+        if wildcard::has_wildcard(url) {
+            let (bucket, pattern) = wildcard::parse_gs_pattern(url)?;
+
+            for md in wildcard::expand_gcs(ctx, &bucket, &pattern).await? {
+                let name = md.name.context("object metadata did not contain a name")?;
+
+                // Skip "directory" placeholder objects
+                if name.ends_with('/') {
+                    continue;
+                }
+
+                cat(
+                    ctx,
+                    &bucket,
+                    &ObjectName::try_from(name.as_str())?,
+                    None,
+                    args.decryption_key.as_ref(),
+                )
+                .await?;
+            }
+        } else {
+            let parsed = url::Url::parse(url)?;
+            let gs_url = util::gs_url_to_object_id(&parsed)?;
+
+            cat(
+                ctx,
+                gs_url.bucket(),
+                gs_url
+                    .object()
+                    .with_context(|| format!("'{url}' is not an object"))?,
+                gs_url.generation(),
+                args.decryption_key.as_ref(),
+            )
+            .await?;
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,115 @@
+use super::{buckets, mb, util};
+use serde::{Deserialize, Serialize};
+
+/// Gets or sets the CORS configuration of a bucket
+#[derive(clap::Subcommand)]
+pub enum Args {
+    /// Prints the CORS configuration of a bucket as JSON
+    Get {
+        /// The gs: URL of the bucket
+        url: String,
+    },
+    /// Replaces the CORS configuration of a bucket with the array of entries in
+    /// a JSON file, eg. `[{"origin": ["*"], "method": ["GET"], "maxAgeSeconds": 3600}]`.
+    /// An empty array removes the CORS configuration
+    Set {
+        /// The gs: URL of the bucket
+        url: String,
+        /// The path of the JSON file, or `-` to read it from stdin
+        file: String,
+    },
+}
+
+#[derive(Deserialize, Serialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+struct Cors {
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    origin: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    method: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    response_header: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max_age_seconds: Option<u32>,
+}
+
+/// The methods that can be used in a CORS configuration
+const METHODS: &[&str] = &["GET", "HEAD", "PUT", "POST", "DELETE", "OPTIONS", "PATCH"];
+
+fn validate(cors: &[Cors]) -> anyhow::Result<()> {
+    for (i, entry) in cors.iter().enumerate() {
+        anyhow::ensure!(
+            !entry.origin.is_empty(),
+            "entry {i}: at least one origin is required"
+        );
+        anyhow::ensure!(
+            !entry.method.is_empty(),
+            "entry {i}: at least one method is required"
+        );
+
+        for method in &entry.method {
+            anyhow::ensure!(
+                METHODS.contains(&method.as_str()),
+                "entry {i}: '{method}' is not a valid method, expected one of {METHODS:?}"
+            );
+        }
+    }
+
+    Ok(())
+}
+
+pub async fn run(ctx: &util::RequestContext, args: Args) -> anyhow::Result<()> {
+    match args {
+        Args::Get { url } => {
+            let bucket = mb::parse_bucket_url(&url)?;
+            let resource = buckets::get(ctx, &bucket, "cors").await?;
+
+            match resource.get("cors") {
+                Some(cors) => println!("{}", serde_json::to_string_pretty(cors)?),
+                None => println!("gs://{bucket} has no CORS configuration"),
+            }
+        }
+        Args::Set { url, file } => {
+            let bucket = mb::parse_bucket_url(&url)?;
+            let cors: Vec<Cors> = buckets::read_config(&file)?;
+            validate(&cors)?;
+
+            let cors = if cors.is_empty() {
+                serde_json::Value::Null
+            } else {
+                serde_json::to_value(&cors)?
+            };
+
+            buckets::patch(ctx, &bucket, &serde_json::json!({ "cors": cors })).await?;
+            println!("Set CORS configuration on gs://{bucket}");
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cors(json: &str) -> anyhow::Result<()> {
+        validate(&serde_json::from_str::<Vec<Cors>>(json)?)
+    }
+
+    #[test]
+    fn validates_entries() {
+        assert!(cors("[]").is_ok());
+        assert!(
+            cors(r#"[{"origin": ["*"], "method": ["GET", "HEAD"], "maxAgeSeconds": 3600}]"#)
+                .is_ok()
+        );
+        assert!(cors(r#"[{"origin": ["https://a.com"], "method": ["PUT"], "responseHeader": ["Content-Type"]}]"#).is_ok());
+
+        assert!(cors(r#"[{"method": ["GET"]}]"#).is_err());
+        assert!(cors(r#"[{"origin": ["*"]}]"#).is_err());
+        assert!(cors(r#"[{"origin": ["*"], "method": ["get"]}]"#).is_err());
+        assert!(cors(r#"[{"origin": ["*"], "method": ["CONNECT"]}]"#).is_err());
+        // Unknown fields are rejected rather than silently dropped
+        assert!(cors(r#"[{"origin": ["*"], "method": ["GET"], "maxAge": 10}]"#).is_err());
+    }
+}
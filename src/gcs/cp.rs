@@ -1,7 +1,23 @@
-use super::util;
+use super::{
+    encryption,
+    progress::Transfer,
+    resumable::{self, UploadMetadata},
+    util, wildcard,
+};
 use anyhow::Context as _;
-use std::{convert::TryFrom, path::PathBuf};
-use tame_gcs::objects::{self, Metadata};
+use futures_util::StreamExt as _;
+use std::{
+    convert::TryFrom,
+    fs,
+    future::Future,
+    ops::Range,
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicBool, Ordering},
+};
+use tame_gcs::{
+    objects::{self, Metadata},
+    BucketName, ObjectName,
+};
 
 #[derive(clap::ValueEnum, Clone, Copy)]
 pub enum Acl {
@@ -13,6 +29,20 @@ pub enum Acl {
     BucketOwnerFullControl,
 }
 
+impl Acl {
+    /// The name of the ACL as it appears in query parameters
+    fn as_str(self) -> &'static str {
+        match self {
+            Self::ProjectPrivate => "projectPrivate",
+            Self::Private => "private",
+            Self::PublicRead => "publicRead",
+            Self::AuthenticatedRead => "authenticatedRead",
+            Self::BucketOwnerRead => "bucketOwnerRead",
+            Self::BucketOwnerFullControl => "bucketOwnerFullControl",
+        }
+    }
+}
+
 impl From<Acl> for tame_gcs::common::PredefinedAcl {
     fn from(a: Acl) -> Self {
         match a {
@@ -26,29 +56,287 @@ impl From<Acl> for tame_gcs::common::PredefinedAcl {
     }
 }
 
-/// Copies a file from one location to another
+/// Options that apply to each individual copy
 #[derive(clap::Parser)]
-pub struct Args {
+pub struct Options {
     /// Predefined ACL to apply to the destination GCS object
     #[clap(short = 'a')]
     predef_acl: Option<Acl>,
-    /// A gs: URL or filepath for the source path to copy from,
-    /// wildcards are not currently supported
+    /// Uploads of files larger than this size are done as resumable uploads,
+    /// sent in chunks so that a failed request doesn't restart the entire upload
+    #[clap(long, default_value = "8MiB", value_parser = crate::parse_byte_size)]
+    resumable_threshold: u64,
+    /// Downloads of objects larger than this size are split into slices that
+    /// are downloaded concurrently, 0 disables sliced downloads
+    #[clap(long, default_value = "150MiB", value_parser = crate::parse_byte_size)]
+    sliced_download_threshold: u64,
+    /// The maximum number of slices to split a sliced download into
+    #[clap(long, default_value = "4")]
+    sliced_download_max_components: u64,
+    /// Uploads of files larger than this size are split into parts that are
+    /// uploaded concurrently as temporary objects, which are then composed into
+    /// the final object. 0 disables parallel composite uploads
+    #[clap(long, default_value = "0", value_parser = crate::parse_byte_size)]
+    parallel_composite_upload_threshold: u64,
+    /// The number of parts to split a parallel composite upload into, GCS can
+    /// only compose up to 32 objects at a time
+    #[clap(long, default_value = "8", value_parser = clap::value_parser!(u64).range(2..=32))]
+    parallel_composite_upload_component_count: u64,
+    /// The Content-Type of uploaded objects. By default this is guessed from
+    /// the file extension of each file
+    #[clap(long)]
+    content_type: Option<String>,
+    /// The Cache-Control of uploaded objects
+    #[clap(long)]
+    cache_control: Option<String>,
+    /// Sets custom metadata on uploaded objects, eg. `-m key=value`
+    #[clap(short = 'm', long = "metadata", value_parser = parse_key_value)]
+    metadata: Vec<(String, String)>,
+    /// Compresses files with gzip before uploading them, setting the
+    /// Content-Encoding of the object to gzip. GCS will transparently
+    /// decompress the object for clients that don't accept gzip
+    #[clap(long, conflicts_with = "zstd")]
+    gzip_local: bool,
+    /// Compresses files with zstd before uploading them, setting the
+    /// Content-Encoding of the object to zstd
+    #[clap(long)]
+    zstd: bool,
+    /// No-clobber, skips files or objects that already exist in the destination
+    #[clap(short = 'n', long, conflicts_with = "if_generation_match")]
+    no_clobber: bool,
+    #[clap(flatten)]
+    preconditions: util::Preconditions,
+    #[clap(flatten)]
+    encryption: encryption::Keys,
+}
+
+impl Options {
+    /// The preconditions for writing to a destination object, no-clobber only
+    /// allows the write if the object doesn't exist yet
+    #[inline]
+    fn preconditions(&self) -> util::Preconditions {
+        util::Preconditions {
+            if_generation_match: if self.no_clobber {
+                Some(0)
+            } else {
+                self.preconditions.if_generation_match
+            },
+            ..self.preconditions
+        }
+    }
+
+    /// Creates the optional parameters for uploads
+    #[inline]
+    fn insert_optional(&self) -> objects::InsertObjectOptional<'_> {
+        objects::InsertObjectOptional {
+            predefined_acl: self.predef_acl.map(Into::into),
+            conditionals: self.preconditions().conditionals(),
+            kms_key_name: self.encryption.kms_key.as_deref(),
+            ..Default::default()
+        }
+    }
+
+    /// Returns true if files are compressed before they are uploaded
+    #[inline]
+    pub(super) fn is_compressed(&self) -> bool {
+        self.compression().is_some()
+    }
+
+    /// Returns true if existing destinations are skipped rather than replaced
+    #[inline]
+    pub(super) fn is_no_clobber(&self) -> bool {
+        self.no_clobber
+    }
+
+    #[inline]
+    fn compression(&self) -> Option<Compression> {
+        if self.gzip_local {
+            Some(Compression::Gzip)
+        } else if self.zstd {
+            Some(Compression::Zstd)
+        } else {
+            None
+        }
+    }
+
+    /// Creates the metadata for an uploaded object, guessing the content type
+    /// from the source path if one wasn't specified, and recording the
+    /// modification time of the source file
+    fn upload_metadata(&self, object: &ObjectName<'_>, src: Option<&Path>) -> UploadMetadata {
+        let mut metadata: std::collections::BTreeMap<_, _> = src
+            .and_then(file_mtime)
+            .map_or_else(Default::default, |mtime| {
+                std::iter::once((MTIME_KEY.to_owned(), mtime.to_string())).collect()
+            });
+        metadata.extend(self.metadata.iter().cloned());
+
+        UploadMetadata {
+            metadata: Metadata {
+                name: Some(object.to_string()),
+                content_type: self
+                    .content_type
+                    .clone()
+                    .or_else(|| src.and_then(guess_content_type).map(String::from)),
+                content_encoding: Some(
+                    self.compression()
+                        .map_or("identity", Compression::content_encoding)
+                        .to_owned(),
+                ),
+                metadata: (!metadata.is_empty()).then_some(metadata),
+                ..Default::default()
+            },
+            cache_control: self.cache_control.clone(),
+        }
+    }
+}
+
+/// The custom metadata key the modification time of an uploaded file is
+/// recorded under, in seconds since the Unix epoch. This is the same key gsutil
+/// uses, so that `rsync` can compare the modification times of files with the
+/// objects they were uploaded as, or downloaded from
+pub(super) const MTIME_KEY: &str = "goog-reserved-file-mtime";
+
+/// The modification time of a file, in seconds since the Unix epoch
+pub(super) fn file_mtime(path: &Path) -> Option<i64> {
+    let modified = fs::metadata(path).ok()?.modified().ok()?;
+    Some(time::OffsetDateTime::from(modified).unix_timestamp())
+}
+
+fn parse_key_value(s: &str) -> Result<(String, String), String> {
+    let (key, value) = s
+        .split_once('=')
+        .ok_or_else(|| format!("'{s}' is not of the form key=value"))?;
+    Ok((key.to_owned(), value.to_owned()))
+}
+
+/// Guesses the content type of a file from its extension, only common types
+/// are recognized, anything else is left for GCS to default to
+/// application/octet-stream
+fn guess_content_type(path: &Path) -> Option<&'static str> {
+    let ext = path.extension()?.to_str()?.to_ascii_lowercase();
+
+    Some(match ext.as_str() {
+        "html" | "htm" => "text/html",
+        "css" => "text/css",
+        "js" | "mjs" => "text/javascript",
+        "json" | "map" => "application/json",
+        "wasm" => "application/wasm",
+        "xml" => "application/xml",
+        "txt" | "log" => "text/plain",
+        "csv" => "text/csv",
+        "md" => "text/markdown",
+        "yaml" | "yml" => "application/yaml",
+        "toml" => "application/toml",
+        "svg" => "image/svg+xml",
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "webp" => "image/webp",
+        "avif" => "image/avif",
+        "ico" => "image/vnd.microsoft.icon",
+        "woff" => "font/woff",
+        "woff2" => "font/woff2",
+        "ttf" => "font/ttf",
+        "otf" => "font/otf",
+        "mp3" => "audio/mpeg",
+        "ogg" => "audio/ogg",
+        "wav" => "audio/wav",
+        "mp4" => "video/mp4",
+        "webm" => "video/webm",
+        "pdf" => "application/pdf",
+        "zip" => "application/zip",
+        "gz" | "tgz" => "application/gzip",
+        "tar" => "application/x-tar",
+        "zst" => "application/zstd",
+        _ => return None,
+    })
+}
+
+#[derive(Clone, Copy)]
+enum Compression {
+    Gzip,
+    Zstd,
+}
+
+impl Compression {
+    #[inline]
+    fn content_encoding(self) -> &'static str {
+        match self {
+            Self::Gzip => "gzip",
+            Self::Zstd => "zstd",
+        }
+    }
+}
+
+/// Compresses a file into a temporary file, which is deleted when dropped
+async fn compress(src: &Path, compression: Compression) -> anyhow::Result<tempfile::TempPath> {
+    let src = src.to_owned();
+
+    tokio::task::spawn_blocking(move || {
+        let mut input =
+            fs::File::open(&src).with_context(|| format!("failed to open '{}'", src.display()))?;
+        let mut output =
+            tempfile::NamedTempFile::new().context("failed to create temporary file")?;
+
+        match compression {
+            Compression::Gzip => {
+                let mut encoder = flate2::write::GzEncoder::new(
+                    output.as_file_mut(),
+                    flate2::Compression::default(),
+                );
+                std::io::copy(&mut input, &mut encoder)?;
+                encoder.finish()?;
+            }
+            Compression::Zstd => {
+                zstd::stream::copy_encode(&mut input, output.as_file_mut(), 0)?;
+            }
+        }
+
+        Ok(output.into_temp_path())
+    })
+    .await?
+}
+
+/// Copies files or directories from one location to another
+#[derive(clap::Parser)]
+pub struct Args {
+    #[clap(flatten)]
+    opts: Options,
+    /// Copies an entire directory tree. Uploads place every file under the
+    /// source directory beneath the destination prefix, downloads write every
+    /// object under the source prefix beneath the destination directory
+    #[clap(short = 'r', long)]
+    recursive: bool,
+    /// The maximum number of files or objects that are copied at once when
+    /// copying a directory tree or wildcard matches
+    #[clap(short = 'j', long, default_value = "4", value_parser = clap::value_parser!(u32).range(1..))]
+    jobs: u32,
+    /// A gs: URL or filepath for the source path to copy from. Supports
+    /// `*`, `**` and `?` wildcards, in which case each match is copied into
+    /// the destination "directory". `-` uploads stdin. A specific generation
+    /// of an object can be copied via `gs://bucket/object#<generation>`
     src_url: String,
     /// A gs: URL or filepath for the destination to copy to,
-    /// wildcards are not currently supported
+    /// wildcards are not supported. `-` downloads to stdout
     dest_url: String,
 }
 
 enum DataPath {
     Gs(util::GsUrl),
+    GsPattern {
+        bucket: BucketName<'static>,
+        pattern: String,
+    },
     Local(PathBuf),
+    LocalPattern(String),
+    /// `-`, stdin when used as the source, stdout when used as the destination
+    Stdio,
 }
 
 impl DataPath {
     #[inline]
     fn is_local(&self) -> bool {
-        matches!(self, Self::Local(_))
+        matches!(self, Self::Local(_) | Self::LocalPattern(_) | Self::Stdio)
     }
 }
 
@@ -56,74 +344,1423 @@ impl TryFrom<String> for DataPath {
     type Error = anyhow::Error;
 
     fn try_from(s: String) -> anyhow::Result<Self> {
-        if s.starts_with("gs://") {
-            let url = url::Url::parse(&s)?;
-            Ok(Self::Gs(util::gs_url_to_object_id(&url)?))
+        if s == "-" {
+            Ok(Self::Stdio)
+        } else if s.starts_with("gs://") {
+            if wildcard::has_wildcard(&s) {
+                let (bucket, pattern) = wildcard::parse_gs_pattern(&s)?;
+                Ok(Self::GsPattern { bucket, pattern })
+            } else {
+                let url = url::Url::parse(&s)?;
+                Ok(Self::Gs(util::gs_url_to_object_id(&url)?))
+            }
+        } else if wildcard::has_wildcard(&s) {
+            Ok(Self::LocalPattern(s))
         } else {
             Ok(Self::Local(PathBuf::from(s)))
         }
     }
 }
 
-// cp is probably gsutil's most complicated subcommand, so we only implement
-// a bare minimum
-pub async fn run(ctx: &util::RequestContext, args: Args) -> anyhow::Result<()> {
-    use std::fs;
-
-    let src = DataPath::try_from(args.src_url)?;
-    let dst = DataPath::try_from(args.dest_url)?;
+/// Joins a relative filesystem path onto an optional object prefix, using `/`
+/// as the separator regardless of the platform
+pub(super) fn join_object_name(
+    prefix: Option<&ObjectName<'_>>,
+    rel: &Path,
+) -> anyhow::Result<ObjectName<'static>> {
+    let mut name = prefix
+        .map(|p| AsRef::<str>::as_ref(p).trim_end_matches('/').to_owned())
+        .unwrap_or_default();
 
-    // Just support gcs to local or vice versa, not local to local or gcs to gcs
-    if src.is_local() == dst.is_local() {
-        let location = if src.is_local() { "local disk" } else { "gcs" };
+    for comp in rel.components() {
+        let comp = comp
+            .as_os_str()
+            .to_str()
+            .with_context(|| format!("path '{}' is not utf-8", rel.display()))?;
 
-        anyhow::bail!("source and destination are both located on {location}")
+        if !name.is_empty() {
+            name.push('/');
+        }
+        name.push_str(comp);
     }
 
-    match (&src, &dst) {
-        (DataPath::Local(ref src), DataPath::Gs(dst)) => {
-            let src_file = fs::File::open(src).context("source path")?;
-            let src_len = src_file.metadata()?.len();
+    Ok(ObjectName::try_from(name)?)
+}
+
+pub(super) async fn upload(
+    ctx: &util::RequestContext,
+    src: &Path,
+    bucket: &BucketName<'_>,
+    object: &ObjectName<'_>,
+    opts: &Options,
+) -> anyhow::Result<()> {
+    let metadata = opts.upload_metadata(object, Some(src));
+
+    // Compress to a temporary file first, so that the compressed data can be
+    // uploaded and verified the same as any other file
+    let compressed = match opts.compression() {
+        Some(compression) => Some(
+            compress(src, compression)
+                .await
+                .with_context(|| format!("failed to compress '{}'", src.display()))?,
+        ),
+        None => None,
+    };
+    let original = src;
+    let src = compressed.as_deref().unwrap_or(src);
+
+    let src_len = fs::metadata(src)
+        .with_context(|| format!("failed to read metadata for '{}'", src.display()))?
+        .len();
 
-            let optional = args.predef_acl.map(|acl| objects::InsertObjectOptional {
-                predefined_acl: Some(acl.into()),
+    let expected_crc = {
+        let path = src.to_owned();
+        tokio::task::spawn_blocking(move || util::file_crc32c(&path)).await??
+    };
+
+    let transfer = ctx
+        .progress
+        .transfer(original.display().to_string(), Some(src_len));
+
+    let uploaded = if opts.parallel_composite_upload_threshold > 0
+        && src_len > opts.parallel_composite_upload_threshold
+    {
+        upload_composite(ctx, src, src_len, bucket, &metadata, opts, &transfer).await
+    } else {
+        upload_range(
+            ctx,
+            src,
+            0..src_len,
+            bucket,
+            &metadata,
+            Some(opts.insert_optional()),
+            opts,
+            &transfer,
+        )
+        .await
+    };
+
+    let uploaded = match uploaded {
+        Ok(uploaded) => uploaded,
+        Err(err) if opts.no_clobber && util::is_precondition_failure(&err) => {
+            ctx.progress
+                .println(format!("Skipping existing 'gs://{bucket}/{object}'"));
+            return Ok(());
+        }
+        Err(err) => {
+            return Err(err.context(format!("failed to upload '{}'", original.display())));
+        }
+    };
+
+    verify_upload(
+        ctx,
+        bucket,
+        object,
+        &uploaded,
+        expected_crc,
+        &original.display().to_string(),
+    )
+    .await
+}
+
+/// Verifies that the crc32c of an uploaded object matches the crc32c of the
+/// source data, deleting the object if it doesn't
+async fn verify_upload(
+    ctx: &util::RequestContext,
+    bucket: &BucketName<'_>,
+    object: &ObjectName<'_>,
+    uploaded: &Metadata,
+    expected_crc: u32,
+    src: &str,
+) -> anyhow::Result<()> {
+    let actual_crc = util::decode_crc32c(
+        uploaded
+            .crc32c
+            .as_deref()
+            .context("uploaded object metadata did not contain a crc32c")?,
+    )?;
+
+    if expected_crc != actual_crc {
+        // Don't leave a corrupt object lying around, but only delete the exact
+        // generation that we uploaded in case it was overwritten in the meantime
+        let del_req = ctx.obj.delete(
+            &(bucket, object),
+            Some(objects::DeleteObjectOptional {
+                generation: uploaded.generation,
                 ..Default::default()
-            });
+            }),
+        )?;
+        let _res = util::execute::<_, objects::DeleteObjectResponse>(ctx, del_req).await;
 
-            let insert_req = ctx.obj.insert_multipart(
-                dst.bucket(),
-                src_file,
-                src_len,
-                &Metadata {
-                    name: dst.object().map(|obn| obn.to_string()),
-                    content_encoding: Some("identity".to_owned()),
+        anyhow::bail!(
+            "crc32c mismatch for '{src}', expected {expected_crc:08x} but uploaded object was {actual_crc:08x}"
+        );
+    }
+
+    Ok(())
+}
+
+/// Uploads a range of a file as a single object
+#[allow(clippy::too_many_arguments)]
+async fn upload_range(
+    ctx: &util::RequestContext,
+    src: &Path,
+    range: Range<u64>,
+    bucket: &BucketName<'_>,
+    metadata: &UploadMetadata,
+    optional: Option<objects::InsertObjectOptional<'_>>,
+    opts: &Options,
+    transfer: &Transfer,
+) -> anyhow::Result<Metadata> {
+    use std::io::{Read, Seek, SeekFrom};
+
+    let mut src_file =
+        fs::File::open(src).with_context(|| format!("failed to open '{}'", src.display()))?;
+
+    let len = range.end - range.start;
+    let key = opts.encryption.encryption_key();
+
+    if len > opts.resumable_threshold {
+        upload_resumable(
+            ctx, src_file, range, bucket, metadata, optional, key, transfer,
+        )
+        .await
+    } else {
+        src_file.seek(SeekFrom::Start(range.start))?;
+
+        let insert_req = encryption::with_key(
+            util::insert_multipart(ctx, bucket, src_file.take(len), len, metadata, optional)?,
+            key,
+        );
+
+        let insert_res: objects::InsertResponse = util::execute(ctx, insert_req).await?;
+        transfer.inc(len);
+        Ok(insert_res.metadata)
+    }
+}
+
+/// Uploads a range of a file in chunks via a resumable upload session, if a
+/// chunk fails we query GCS for how much of the file it has actually committed
+/// and resume from that offset
+#[allow(clippy::too_many_arguments)]
+async fn upload_resumable(
+    ctx: &util::RequestContext,
+    mut src_file: fs::File,
+    src_range: Range<u64>,
+    bucket: &BucketName<'_>,
+    metadata: &UploadMetadata,
+    optional: Option<objects::InsertObjectOptional<'_>>,
+    key: Option<&encryption::Key>,
+    transfer: &Transfer,
+) -> anyhow::Result<Metadata> {
+    use std::io::{Read, Seek, SeekFrom};
+
+    let init_req = encryption::with_key(
+        resumable::init_request(&ctx.obj, bucket, metadata, optional)?,
+        key,
+    );
+    let mut upload = resumable::Upload::new(
+        util::execute_raw(ctx, init_req).await?,
+        src_range.end - src_range.start,
+    )?;
+
+    loop {
+        let req = if upload.needs_status() {
+            upload.status_request()?
+        } else {
+            let range = upload.next_chunk();
+            let mut chunk = vec![0u8; (range.end - range.start) as usize];
+
+            src_file.seek(SeekFrom::Start(src_range.start + range.start))?;
+            src_file.read_exact(&mut chunk)?;
+
+            upload.chunk_request(chunk)?
+        };
+        let res = util::execute_raw(ctx, encryption::with_key(req, key)).await;
+
+        let committed = upload.offset();
+        let step = upload.handle_result(res, &ctx.retry)?;
+        transfer.inc(upload.offset().saturating_sub(committed));
+
+        match step {
+            resumable::Step::Complete(metadata) => return Ok(metadata),
+            resumable::Step::Next => {}
+            resumable::Step::Resume(delay) => tokio::time::sleep(delay).await,
+        }
+    }
+}
+
+/// Uploads stdin as a single object. As the length of stdin isn't known up
+/// front, it is always uploaded via a streaming resumable upload
+async fn upload_stdin(
+    ctx: &util::RequestContext,
+    bucket: &BucketName<'_>,
+    object: &ObjectName<'_>,
+    opts: &Options,
+) -> anyhow::Result<()> {
+    anyhow::ensure!(
+        opts.compression().is_none(),
+        "compression is not supported when uploading stdin"
+    );
+
+    let metadata = opts.upload_metadata(object, None);
+    let transfer = ctx.progress.transfer("-", None);
+
+    let (uploaded, expected_crc) = match upload_stream(
+        ctx,
+        tokio::io::stdin(),
+        bucket,
+        &metadata,
+        Some(opts.insert_optional()),
+        opts.encryption.encryption_key(),
+        &transfer,
+    )
+    .await
+    {
+        Ok(uploaded) => uploaded,
+        Err(err) if opts.no_clobber && util::is_precondition_failure(&err) => {
+            ctx.progress
+                .println(format!("Skipping existing 'gs://{bucket}/{object}'"));
+            return Ok(());
+        }
+        Err(err) => return Err(err.context("failed to upload stdin")),
+    };
+
+    verify_upload(ctx, bucket, object, &uploaded, expected_crc, "-").await
+}
+
+/// Uploads a stream of unknown length in chunks via a resumable upload session.
+/// Only the current chunk is buffered, so if a chunk fails GCS must have
+/// committed everything before it, which it always does as it only commits
+/// whole chunks. Returns the crc32c of the stream along with the metadata
+async fn upload_stream(
+    ctx: &util::RequestContext,
+    mut src: impl tokio::io::AsyncRead + Unpin,
+    bucket: &BucketName<'_>,
+    metadata: &UploadMetadata,
+    optional: Option<objects::InsertObjectOptional<'_>>,
+    key: Option<&encryption::Key>,
+    transfer: &Transfer,
+) -> anyhow::Result<(Metadata, u32)> {
+    use tokio::io::AsyncReadExt;
+
+    let init_req = encryption::with_key(
+        resumable::init_request(&ctx.obj, bucket, metadata, optional)?,
+        key,
+    );
+    let mut upload = resumable::Upload::streaming(util::execute_raw(ctx, init_req).await?)?;
+
+    let mut chunk = Vec::new();
+    let mut chunk_start = 0;
+    let mut crc = 0;
+
+    loop {
+        let req = if upload.needs_status() {
+            upload.status_request()?
+        } else {
+            // Only read the next chunk once the current one has been committed
+            if upload.total().is_none() && upload.offset() >= chunk_start + chunk.len() as u64 {
+                chunk_start += chunk.len() as u64;
+                chunk.clear();
+
+                (&mut src)
+                    .take(resumable::CHUNK_SIZE)
+                    .read_to_end(&mut chunk)
+                    .await
+                    .context("failed to read input")?;
+
+                crc = crc32c::crc32c_append(crc, &chunk);
+
+                // A short read means we've reached the end of the stream
+                if (chunk.len() as u64) < resumable::CHUNK_SIZE {
+                    upload.set_total(chunk_start + chunk.len() as u64);
+                }
+            }
+
+            let committed = (upload.offset() - chunk_start) as usize;
+
+            // Once the total is known and everything has been sent, the status
+            // request finalizes the upload
+            if committed < chunk.len() {
+                upload.chunk_request(chunk[committed..].to_vec())?
+            } else {
+                upload.status_request()?
+            }
+        };
+        let res = util::execute_raw(ctx, encryption::with_key(req, key)).await;
+
+        let committed = upload.offset();
+        let step = upload.handle_result(res, &ctx.retry)?;
+        transfer.inc(upload.offset().saturating_sub(committed));
+
+        match step {
+            resumable::Step::Complete(metadata) => return Ok((metadata, crc)),
+            resumable::Step::Next => anyhow::ensure!(
+                upload.offset() >= chunk_start,
+                "GCS committed fewer bytes than were previously acknowledged"
+            ),
+            resumable::Step::Resume(delay) => tokio::time::sleep(delay).await,
+        }
+    }
+}
+
+/// Uploads a file as several parts concurrently, each as its own temporary
+/// object, which are then composed into the final object. The temporary objects
+/// are deleted afterwards regardless of whether the upload succeeded or not
+async fn upload_composite(
+    ctx: &util::RequestContext,
+    src: &Path,
+    src_len: u64,
+    bucket: &BucketName<'_>,
+    metadata: &UploadMetadata,
+    opts: &Options,
+    transfer: &Transfer,
+) -> anyhow::Result<Metadata> {
+    let name = metadata
+        .metadata
+        .name
+        .as_deref()
+        .context("object metadata must contain a name")?;
+
+    let count = opts.parallel_composite_upload_component_count;
+    let part_len = (src_len + count - 1) / count;
+
+    // Include a timestamp in the part names so that concurrent uploads to the
+    // same object don't clobber each other's parts
+    let nonce = time::OffsetDateTime::now_utc().unix_timestamp_nanos();
+
+    let parts: Vec<_> = (0..count)
+        .map(|i| {
+            (
+                format!("{name}.boh-part-{nonce}-{i}"),
+                i * part_len..std::cmp::min((i + 1) * part_len, src_len),
+            )
+        })
+        .filter(|(_, range)| !range.is_empty())
+        .collect();
+
+    let res = async {
+        futures_util::future::try_join_all(parts.iter().map(|(part_name, range)| async move {
+            let part_md = UploadMetadata {
+                metadata: Metadata {
+                    name: Some(part_name.clone()),
                     ..Default::default()
                 },
-                optional,
-            )?;
+                ..Default::default()
+            };
+
+            upload_range(
+                ctx,
+                src,
+                range.clone(),
+                bucket,
+                &part_md,
+                None,
+                opts,
+                transfer,
+            )
+            .await
+            .with_context(|| format!("failed to upload part '{part_name}'"))
+        }))
+        .await?;
+
+        compose(
+            ctx,
+            bucket,
+            metadata,
+            parts.iter().map(|(part_name, _)| part_name.as_str()),
+            opts,
+        )
+        .await
+    }
+    .await;
+
+    // Failing to delete a part doesn't fail the upload itself, and parts that
+    // failed to upload in the first place won't exist
+    futures_util::future::join_all(parts.iter().map(|(part_name, _)| async move {
+        let object = ObjectName::try_from(part_name.as_str())?;
+        let del_req = ctx.obj.delete(&(bucket, &object), None)?;
+        util::execute::<_, objects::DeleteObjectResponse>(ctx, del_req).await
+    }))
+    .await;
+
+    res
+}
+
+/// Concatenates the source objects, in order, into the object described by the
+/// metadata, which must be in the same bucket
+///
+/// [Complete API Documentation](https://cloud.google.com/storage/docs/json_api/v1/objects/compose)
+async fn compose<'a>(
+    ctx: &util::RequestContext,
+    bucket: &BucketName<'_>,
+    metadata: &UploadMetadata,
+    sources: impl Iterator<Item = &'a str>,
+    opts: &Options,
+) -> anyhow::Result<Metadata> {
+    use tame_gcs::http;
+
+    let object = ObjectName::try_from(
+        metadata
+            .metadata
+            .name
+            .as_deref()
+            .context("object metadata must contain a name")?,
+    )?;
+
+    let mut uri = util::object_url(ctx, bucket, &object, "compose")?;
+
+    let query = {
+        let mut ser = url::form_urlencoded::Serializer::new(String::new());
+
+        if let Some(acl) = opts.predef_acl {
+            ser.append_pair("destinationPredefinedAcl", acl.as_str());
+        }
+
+        let preconditions = opts.preconditions();
+        if let Some(gen) = preconditions.if_generation_match {
+            ser.append_pair("ifGenerationMatch", &gen.to_string());
+        }
+        if let Some(mgen) = preconditions.if_metageneration_match {
+            ser.append_pair("ifMetagenerationMatch", &mgen.to_string());
+        }
+        if let Some(kms_key) = &opts.encryption.kms_key {
+            ser.append_pair("kmsKeyName", kms_key);
+        }
+
+        ser.finish()
+    };
 
-            let _insert_res: objects::InsertResponse = util::execute(ctx, insert_req).await?;
+    if !query.is_empty() {
+        uri.push('?');
+        uri.push_str(&query);
+    }
+
+    let body = serde_json::to_vec(&serde_json::json!({
+        "sourceObjects": sources.map(|name| serde_json::json!({ "name": name })).collect::<Vec<_>>(),
+        "destination": metadata,
+    }))?;
+
+    let compose_req = http::Request::builder()
+        .method(http::Method::POST)
+        .uri(uri)
+        .header(http::header::CONTENT_TYPE, "application/json")
+        .header(http::header::CONTENT_LENGTH, body.len())
+        .body(std::io::Cursor::new(body))?;
+    let compose_req = encryption::with_key(compose_req, opts.encryption.encryption_key());
+
+    let compose_res: objects::InsertResponse = util::execute(ctx, compose_req).await?;
+    Ok(compose_res.metadata)
+}
+
+/// Checks if an object can be downloaded as slices. GCS ignores the Range
+/// header for objects stored with a Content-Encoding such as gzip, and serves
+/// the entire object decompressed instead
+fn can_slice(metadata: &Metadata, opts: &Options) -> bool {
+    metadata.size.unwrap_or_default() > opts.sliced_download_threshold
+        && metadata
+            .content_encoding
+            .as_deref()
+            .map_or(true, |ce| ce.is_empty() || ce == "identity")
+}
+
+/// Downloads an object, or a specific generation of it, to a local file. The
+/// object's metadata, if already known from eg. a listing, is used to decide
+/// whether to do a sliced download, otherwise it is retrieved first
+pub(super) async fn download(
+    ctx: &util::RequestContext,
+    bucket: &BucketName<'_>,
+    object: &ObjectName<'_>,
+    generation: Option<i64>,
+    metadata: Option<&Metadata>,
+    dst: &Path,
+    opts: &Options,
+) -> anyhow::Result<()> {
+    if opts.no_clobber && dst.exists() {
+        ctx.progress
+            .println(format!("Skipping existing '{}'", dst.display()));
+        return Ok(());
+    }
+
+    let key = opts.encryption.decryption_key();
+
+    if opts.sliced_download_threshold > 0 && opts.sliced_download_max_components > 1 {
+        let retrieved;
+        let metadata = match metadata {
+            Some(metadata) => metadata,
+            None => {
+                let get_req = encryption::with_key(
+                    ctx.obj.get(
+                        &(bucket, object),
+                        Some(objects::GetObjectOptional {
+                            generation,
+                            ..Default::default()
+                        }),
+                    )?,
+                    key,
+                );
+                let get_res: objects::GetObjectResponse = util::execute(ctx, get_req).await?;
+                retrieved = get_res.metadata;
+                &retrieved
+            }
+        };
 
+        if can_slice(metadata, opts) {
+            return download_sliced(ctx, bucket, object, dst, metadata, opts).await;
+        }
+    }
+
+    let dl_req = encryption::with_key(
+        ctx.obj.download(
+            &(bucket, object),
+            Some(objects::DownloadObjectOptional {
+                generation,
+                ..Default::default()
+            }),
+        )?,
+        key,
+    );
+
+    let response = util::execute_stream(ctx, dl_req).await?;
+    let transfer = ctx
+        .progress
+        .transfer(format!("gs://{bucket}/{object}"), response.content_length());
+
+    let res = async {
+        let dst_file = fs::File::create(dst)
+            .with_context(|| format!("failed to create '{}'", dst.display()))?;
+
+        write_response(
+            ctx,
+            &format!("gs://{bucket}/{object}"),
+            response,
+            dst_file,
+            Some(&transfer),
+        )
+        .await
+        .with_context(|| format!("failed to write to '{}'", dst.display()))
+    }
+    .await;
+
+    // Don't leave a partial or corrupt file behind
+    if res.is_err() {
+        let _ = fs::remove_file(dst);
+    }
+
+    res
+}
+
+/// Writes the body of a download response to the destination, verifying
+/// the crc32c of the data against the one GCS calculated for the object, or
+/// its md5 if it doesn't have a crc32c. A warning is printed if the object has
+/// neither, or was decompressed by GCS, as the data can't be verified at all
+pub(super) async fn write_response(
+    ctx: &util::RequestContext,
+    name: &str,
+    mut response: reqwest::Response,
+    mut dst: impl std::io::Write,
+    transfer: Option<&Transfer>,
+) -> anyhow::Result<()> {
+    let expected = util::response_hashes(response.headers())?;
+
+    let mut actual_crc = 0;
+    let mut actual_md5 = if expected.crc32c.is_none() && expected.md5.is_some() {
+        Some(openssl::hash::Hasher::new(
+            openssl::hash::MessageDigest::md5(),
+        )?)
+    } else {
+        None
+    };
+
+    // Write each chunk as it arrives so that we never hold more than a chunk
+    // of the object in memory, regardless of its size
+    while let Some(chunk) = response
+        .chunk()
+        .await
+        .context("failed to receive response body")?
+    {
+        ctx.throttle.consume(chunk.len() as u64).await;
+        dst.write_all(&chunk)?;
+        actual_crc = crc32c::crc32c_append(actual_crc, &chunk);
+
+        if let Some(md5) = &mut actual_md5 {
+            md5.update(&chunk)?;
+        }
+
+        if let Some(transfer) = transfer {
+            transfer.inc(chunk.len() as u64);
+        }
+    }
+
+    dst.flush()?;
+
+    match (expected.crc32c, expected.md5, actual_md5) {
+        (Some(expected_crc), ..) => {
+            anyhow::ensure!(
+                expected_crc == actual_crc,
+                "crc32c mismatch, expected {expected_crc:08x} but downloaded data was {actual_crc:08x}"
+            );
+        }
+        (None, Some(expected_md5), Some(mut actual_md5)) => {
+            verify_md5(&expected_md5, &actual_md5.finish()?)?;
+        }
+        _ if expected.transcoded => ctx.progress.warn(format!(
+            "'{name}' was not verified because the object was decompressively transcoded by GCS"
+        )),
+        _ => ctx.progress.warn(format!(
+            "'{name}' has no crc32c or md5 hash, the downloaded data was not verified"
+        )),
+    }
+
+    Ok(())
+}
+
+/// Checks that the md5 of downloaded data matches the object's
+fn verify_md5(expected: &[u8], actual: &[u8]) -> anyhow::Result<()> {
+    use base64::Engine;
+
+    let engine = &base64::engine::general_purpose::STANDARD;
+    anyhow::ensure!(
+        expected == actual,
+        "md5 mismatch, expected {} but downloaded data was {}",
+        engine.encode(expected),
+        engine.encode(actual)
+    );
+
+    Ok(())
+}
+
+/// Downloads an object as several slices via concurrent ranged requests, each
+/// of which writes to its own region of the preallocated destination file. As
+/// the slices are not validated individually, the entire file is checked
+/// against the object's crc32c once all of the slices are finished
+async fn download_sliced(
+    ctx: &util::RequestContext,
+    bucket: &BucketName<'_>,
+    object: &ObjectName<'_>,
+    dst: &Path,
+    metadata: &Metadata,
+    opts: &Options,
+) -> anyhow::Result<()> {
+    let size = metadata
+        .size
+        .context("object metadata did not contain a size")?;
+
+    let dst_file =
+        fs::File::create(dst).with_context(|| format!("failed to create '{}'", dst.display()))?;
+    dst_file
+        .set_len(size)
+        .with_context(|| format!("failed to allocate '{}'", dst.display()))?;
+    drop(dst_file);
+
+    let count = opts.sliced_download_max_components;
+    let slice_len = (size + count - 1) / count;
+
+    let transfer = ctx
+        .progress
+        .transfer(format!("gs://{bucket}/{object}"), Some(size));
+
+    let slices = (0..count)
+        .map(|i| i * slice_len..std::cmp::min((i + 1) * slice_len, size))
+        .filter(|range| !range.is_empty())
+        // Pin every slice to the same generation in case the object is
+        // overwritten while we're downloading it
+        .map(|range| {
+            download_slice(
+                ctx,
+                bucket,
+                object,
+                metadata.generation,
+                dst,
+                range,
+                opts.encryption.decryption_key(),
+                &transfer,
+            )
+        });
+
+    let res = async {
+        futures_util::future::try_join_all(slices).await?;
+
+        let path = dst.to_owned();
+        if let Some(expected) = &metadata.crc32c {
+            let expected = util::decode_crc32c(expected)?;
+            let actual = tokio::task::spawn_blocking(move || util::file_crc32c(&path)).await??;
+
+            anyhow::ensure!(
+                expected == actual,
+                "crc32c mismatch, expected {expected:08x} but downloaded data was {actual:08x}"
+            );
+        } else if let Some(expected) = &metadata.md5_hash {
+            let expected = util::decode_md5(expected)?;
+            let actual = tokio::task::spawn_blocking(move || util::file_md5(&path)).await??;
+
+            verify_md5(&expected, &actual)?;
+        } else {
+            ctx.progress.warn(format!(
+                "'gs://{bucket}/{object}' has no crc32c or md5 hash, the downloaded data was not verified"
+            ));
+        }
+
+        Ok(())
+    }
+    .await;
+
+    if res.is_err() {
+        let _ = fs::remove_file(dst);
+    }
+
+    res
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn download_slice(
+    ctx: &util::RequestContext,
+    bucket: &BucketName<'_>,
+    object: &ObjectName<'_>,
+    generation: Option<i64>,
+    dst: &Path,
+    range: Range<u64>,
+    key: Option<&encryption::Key>,
+    transfer: &Transfer,
+) -> anyhow::Result<()> {
+    use std::io::{Seek, SeekFrom, Write};
+    use tame_gcs::http;
+
+    let mut dl_req = encryption::with_key(
+        ctx.obj.download(
+            &(bucket, object),
+            Some(objects::DownloadObjectOptional {
+                generation,
+                ..Default::default()
+            }),
+        )?,
+        key,
+    );
+
+    dl_req.headers_mut().insert(
+        http::header::RANGE,
+        http::HeaderValue::try_from(format!("bytes={}-{}", range.start, range.end - 1))?,
+    );
+
+    let mut response = util::execute_stream(ctx, dl_req).await?;
+
+    let mut dst_file = fs::OpenOptions::new()
+        .write(true)
+        .open(dst)
+        .with_context(|| format!("failed to open '{}'", dst.display()))?;
+    dst_file.seek(SeekFrom::Start(range.start))?;
+
+    let mut written = 0;
+    while let Some(chunk) = response
+        .chunk()
+        .await
+        .context("failed to receive response body")?
+    {
+        ctx.throttle.consume(chunk.len() as u64).await;
+        dst_file
+            .write_all(&chunk)
+            .with_context(|| format!("failed to write to '{}'", dst.display()))?;
+        written += chunk.len() as u64;
+        transfer.inc(chunk.len() as u64);
+    }
+
+    anyhow::ensure!(
+        written == range.end - range.start,
+        "expected {} bytes for slice {range:?}, but received {written}",
+        range.end - range.start
+    );
+
+    Ok(())
+}
+
+/// Copies an object to another location in GCS without the data ever leaving
+/// GCS. Large objects, or copies between locations or storage classes, can
+/// take multiple rewrite calls to complete, each of which must pass along the
+/// token returned by the previous call. If metadata is specified, it replaces
+/// the metadata of the source object rather than it being copied as is. The
+/// source preconditions only allow the copy if the source object is still the
+/// generation and metageneration it was expected to be
+#[allow(clippy::too_many_arguments)]
+pub(super) async fn rewrite(
+    ctx: &util::RequestContext,
+    src: (&BucketName<'_>, &ObjectName<'_>),
+    src_generation: Option<i64>,
+    src_preconditions: util::Preconditions,
+    dst: (&BucketName<'_>, &ObjectName<'_>),
+    metadata: Option<&serde_json::Value>,
+    predef_acl: Option<Acl>,
+    preconditions: util::Preconditions,
+    encryption: &encryption::Keys,
+) -> anyhow::Result<()> {
+    let mut rewrite_token = None;
+
+    loop {
+        let mut rewrite_req = ctx.obj.rewrite(
+            &src,
+            &dst,
+            rewrite_token.take(),
+            None,
+            Some(objects::RewriteObjectOptional {
+                destination_predefined_acl: predef_acl.map(|acl| acl.as_str().to_owned()),
+                destination_conditionals: Some(preconditions.conditionals()),
+                destination_kms_key_name: encryption.kms_key.clone(),
+                source_generation: src_generation,
+                if_source_generation_match: src_preconditions.if_generation_match,
+                if_source_metageneration_match: src_preconditions.if_metageneration_match,
+                ..Default::default()
+            }),
+        )?;
+
+        if let Some(key) = encryption.decryption_key() {
+            key.apply_copy_source(&mut rewrite_req);
+        }
+        rewrite_req = encryption::with_key(rewrite_req, encryption.encryption_key());
+
+        if let Some(metadata) = metadata {
+            rewrite_req = util::with_json_body(rewrite_req, metadata)?;
+        }
+
+        let res: objects::RewriteObjectResponse = util::execute(ctx, rewrite_req).await?;
+
+        if res.done {
+            return Ok(());
+        }
+
+        rewrite_token = Some(
+            res.rewrite_token
+                .context("rewrite is not done, but no rewrite token was returned")?,
+        );
+    }
+}
+
+/// Copies an object to another location in GCS via [`rewrite`], skipping
+/// objects that already exist in the destination in no-clobber mode
+async fn copy_object(
+    ctx: &util::RequestContext,
+    src: (&BucketName<'_>, &ObjectName<'_>),
+    src_generation: Option<i64>,
+    dst: (&BucketName<'_>, &ObjectName<'_>),
+    opts: &Options,
+) -> anyhow::Result<()> {
+    match rewrite(
+        ctx,
+        src,
+        src_generation,
+        util::Preconditions::default(),
+        dst,
+        None,
+        opts.predef_acl,
+        opts.preconditions(),
+        &opts.encryption,
+    )
+    .await
+    {
+        Err(err) if opts.no_clobber && util::is_precondition_failure(&err) => {
+            ctx.progress
+                .println(format!("Skipping existing 'gs://{}/{}'", dst.0, dst.1));
             Ok(())
         }
-        (DataPath::Gs(src), DataPath::Local(dst)) => {
-            let mut dst_file = fs::File::create(dst).context("destination path")?;
+        res => res,
+    }
+}
+
+/// Lists every object beneath the prefix, returning the full name of each
+/// object along with its name relative to the prefix, and its metadata
+async fn list_dir(
+    ctx: &util::RequestContext,
+    src: &util::GsUrl,
+) -> anyhow::Result<Vec<(String, String, Metadata)>> {
+    // Treat the source as a "directory" so that eg. gs://bucket/dir doesn't
+    // also match gs://bucket/dir-other/
+    let prefix = src.object().map(|obn| {
+        let mut prefix = obn.to_string();
+        if !prefix.ends_with('/') {
+            prefix.push('/');
+        }
+        prefix
+    });
+
+    let objects = util::list_objects(ctx, src.bucket(), prefix.as_deref()).await?;
+
+    let mut names = Vec::with_capacity(objects.len());
+    for md in objects {
+        let name = md
+            .name
+            .clone()
+            .context("object metadata did not contain a name")?;
+        let rel = name[prefix.as_ref().map_or(0, |p| p.len())..].to_owned();
+
+        // Skip "directory" placeholder objects
+        if rel.is_empty() || rel.ends_with('/') {
+            continue;
+        }
+
+        names.push((name, rel, md));
+    }
+
+    Ok(names)
+}
+
+/// Maps an object's name relative to a prefix onto a path beneath the local
+/// directory, refusing names that would escape the directory. Each `/`
+/// separated part of the name must be a single plain path component on the
+/// current platform, eg. `a\b` or `C:` are rejected on Windows
+pub(super) fn local_path(dir: &Path, name: &str, rel: &str) -> anyhow::Result<PathBuf> {
+    use std::path::Component;
+
+    let mut path = dir.to_owned();
+    for comp in rel.split('/') {
+        let mut comps = Path::new(comp).components();
+        let is_plain = matches!(
+            (comps.next(), comps.next()),
+            (Some(Component::Normal(normal)), None) if normal == comp
+        );
+
+        // Alternate data streams, eg. `file:stream`, are a single component
+        let is_plain = is_plain && !(cfg!(windows) && comp.contains(':'));
+
+        anyhow::ensure!(is_plain, "object '{name}' can't be mapped to a local path");
+        path.push(comp);
+    }
+
+    Ok(path)
+}
 
-            let dl_req = ctx.obj.download(
-                &(
+/// Collects every file beneath a directory, following symbolic links so that
+/// linked files and directories are included. Links that are broken, or that
+/// point to one of their own ancestors, are skipped with a warning
+pub(super) fn walk_files(
+    walker: walkdir::WalkDir,
+    progress: &super::progress::Progress,
+) -> anyhow::Result<Vec<walkdir::DirEntry>> {
+    let mut files = Vec::new();
+
+    for entry in walker.follow_links(true) {
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(err) => {
+                let is_broken_link = err
+                    .io_error()
+                    .map_or(false, |io| io.kind() == std::io::ErrorKind::NotFound)
+                    && err
+                        .path()
+                        .map_or(false, |path| path.symlink_metadata().is_ok());
+
+                if err.loop_ancestor().is_none() && !is_broken_link {
+                    return Err(err).context("failed to walk directory");
+                }
+
+                progress.warn(format!(
+                    "skipping '{}': {err}",
+                    err.path().unwrap_or_else(|| Path::new("")).display()
+                ));
+                continue;
+            }
+        };
+
+        if entry.file_type().is_file() {
+            files.push(entry);
+        }
+    }
+
+    Ok(files)
+}
+
+/// Runs the copy for each item, at most `jobs` at a time. Once a copy fails no
+/// more are started, and the first error is returned once the copies that are
+/// already in progress have finished, so that they can clean up after
+/// themselves
+pub(super) async fn copy_all<T, F, Fut>(jobs: u32, items: Vec<T>, copy: F) -> anyhow::Result<()>
+where
+    F: Fn(T) -> Fut,
+    Fut: Future<Output = anyhow::Result<()>>,
+{
+    let failed = AtomicBool::new(false);
+
+    let mut copies = futures_util::stream::iter(items)
+        .map(|item| {
+            let failed = &failed;
+            let copy = copy(item);
+
+            async move {
+                if failed.load(Ordering::Relaxed) {
+                    return Ok(());
+                }
+
+                copy.await
+            }
+        })
+        .buffer_unordered(jobs as usize);
+
+    let mut first_err = None;
+    while let Some(res) = copies.next().await {
+        if let Err(err) = res {
+            failed.store(true, Ordering::Relaxed);
+            first_err.get_or_insert(err);
+        }
+    }
+
+    first_err.map_or(Ok(()), Err)
+}
+
+/// Uploads every file in the source directory tree beneath the destination prefix
+async fn upload_dir(
+    ctx: &util::RequestContext,
+    src: &Path,
+    dst: &util::GsUrl,
+    opts: &Options,
+    jobs: u32,
+) -> anyhow::Result<()> {
+    anyhow::ensure!(src.is_dir(), "'{}' is not a directory", src.display());
+
+    let mut uploads = Vec::new();
+    for entry in walk_files(walkdir::WalkDir::new(src), &ctx.progress)? {
+        let rel = entry.path().strip_prefix(src)?;
+        uploads.push((join_object_name(dst.object(), rel)?, entry.into_path()));
+    }
+
+    copy_all(jobs, uploads, |(object, path)| async move {
+        upload(ctx, &path, dst.bucket(), &object, opts)
+            .await
+            .with_context(|| format!("failed to upload '{}'", path.display()))
+    })
+    .await
+}
+
+/// Downloads each object, along with its metadata from the listing, to its
+/// local path, creating any missing parent directories
+async fn download_all(
+    ctx: &util::RequestContext,
+    bucket: &BucketName<'_>,
+    downloads: Vec<(String, Metadata, PathBuf)>,
+    opts: &Options,
+    jobs: u32,
+) -> anyhow::Result<()> {
+    copy_all(jobs, downloads, |(name, md, path)| async move {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)
+                .with_context(|| format!("failed to create '{}'", parent.display()))?;
+        }
+
+        let object = ObjectName::try_from(name.as_str())?;
+        download(ctx, bucket, &object, None, Some(&md), &path, opts)
+            .await
+            .with_context(|| format!("failed to download '{name}'"))
+    })
+    .await
+}
+
+/// Downloads every object beneath the source prefix into the destination
+/// directory, recreating the relative paths of each object
+async fn download_dir(
+    ctx: &util::RequestContext,
+    src: &util::GsUrl,
+    dst: &Path,
+    opts: &Options,
+    jobs: u32,
+) -> anyhow::Result<()> {
+    let downloads = list_dir(ctx, src)
+        .await?
+        .into_iter()
+        .map(|(name, rel, md)| {
+            let path = local_path(dst, &name, &rel)?;
+            Ok((name, md, path))
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+
+    download_all(ctx, src.bucket(), downloads, opts, jobs).await
+}
+
+/// Copies each object to its destination object
+async fn rewrite_all(
+    ctx: &util::RequestContext,
+    src_bucket: &BucketName<'_>,
+    dst_bucket: &BucketName<'_>,
+    copies: Vec<(String, ObjectName<'static>)>,
+    opts: &Options,
+    jobs: u32,
+) -> anyhow::Result<()> {
+    copy_all(jobs, copies, |(name, dst_object)| async move {
+        let src_object = ObjectName::try_from(name.as_str())?;
+
+        copy_object(
+            ctx,
+            (src_bucket, &src_object),
+            None,
+            (dst_bucket, &dst_object),
+            opts,
+        )
+        .await
+        .with_context(|| format!("failed to copy '{name}'"))
+    })
+    .await
+}
+
+/// Copies every object beneath the source prefix to the same relative name
+/// beneath the destination prefix
+async fn rewrite_dir(
+    ctx: &util::RequestContext,
+    src: &util::GsUrl,
+    dst: &util::GsUrl,
+    opts: &Options,
+    jobs: u32,
+) -> anyhow::Result<()> {
+    let copies = list_dir(ctx, src)
+        .await?
+        .into_iter()
+        .map(|(name, rel, _md)| Ok((name, join_object_name(dst.object(), Path::new(&rel))?)))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+
+    rewrite_all(ctx, src.bucket(), dst.bucket(), copies, opts, jobs).await
+}
+
+// cp is probably gsutil's most complicated subcommand, so we only implement
+// a bare minimum
+pub async fn run(ctx: &util::RequestContext, args: Args) -> anyhow::Result<()> {
+    let src = DataPath::try_from(args.src_url)?;
+    let dst = DataPath::try_from(args.dest_url)?;
+
+    anyhow::ensure!(
+        !matches!(dst, DataPath::GsPattern { .. } | DataPath::LocalPattern(_)),
+        "wildcards are not supported in the destination"
+    );
+
+    // A write always creates a new generation of the destination object
+    anyhow::ensure!(
+        !matches!(&dst, DataPath::Gs(gs_url) if gs_url.generation().is_some()),
+        "generations are not supported in the destination, use --if-generation-match instead"
+    );
+
+    // Copying from local disk to local disk is out of scope
+    anyhow::ensure!(
+        !(src.is_local() && dst.is_local()),
+        "source and destination are both located on local disk"
+    );
+
+    anyhow::ensure!(
+        !(args.recursive && matches!((&src, &dst), (DataPath::Stdio, _) | (_, DataPath::Stdio))),
+        "'-' can't be used with -r"
+    );
+
+    // Wildcards already select exactly which files or objects are copied, use
+    // `**` to match them in subdirectories
+    anyhow::ensure!(
+        !(args.recursive && matches!(src, DataPath::GsPattern { .. } | DataPath::LocalPattern(_))),
+        "-r can't be used with wildcards, use `**` to match files in subdirectories instead"
+    );
+
+    match (&src, &dst) {
+        (DataPath::Local(src), DataPath::Gs(dst)) => {
+            if args.recursive {
+                upload_dir(ctx, src, dst, &args.opts, args.jobs).await
+            } else {
+                upload(
+                    ctx,
+                    src,
+                    dst.bucket(),
+                    dst.object()
+                        .context("must provide a full object name to copy to")?,
+                    &args.opts,
+                )
+                .await
+            }
+        }
+        (DataPath::Gs(src), DataPath::Local(dst)) => {
+            if args.recursive {
+                download_dir(ctx, src, dst, &args.opts, args.jobs).await
+            } else {
+                download(
+                    ctx,
                     src.bucket(),
                     src.object()
                         .context("must provide a full object name to copy from")?,
-                ),
-                None,
-            )?;
+                    src.generation(),
+                    None,
+                    dst,
+                    &args.opts,
+                )
+                .await
+            }
+        }
+        (DataPath::LocalPattern(pattern), DataPath::Gs(dst)) => {
+            // Matches keep their path relative to the pattern so that files
+            // with the same name in different directories don't overwrite
+            // each other
+            let uploads = wildcard::expand_local(pattern)?
+                .into_iter()
+                .map(|(path, rel)| Ok((join_object_name(dst.object(), Path::new(&rel))?, path)))
+                .collect::<anyhow::Result<Vec<_>>>()?;
 
-            let mut response: objects::DownloadObjectResponse = util::execute(ctx, dl_req).await?;
+            let opts = &args.opts;
+            copy_all(args.jobs, uploads, |(object, path)| async move {
+                upload(ctx, &path, dst.bucket(), &object, opts)
+                    .await
+                    .with_context(|| format!("failed to upload '{}'", path.display()))
+            })
+            .await
+        }
+        (DataPath::GsPattern { bucket, pattern }, DataPath::Local(dst)) => {
+            let mut downloads = Vec::new();
+            for md in wildcard::expand_gcs(ctx, bucket, pattern).await? {
+                let name = md
+                    .name
+                    .clone()
+                    .context("object metadata did not contain a name")?;
+                let rel = wildcard::relative_name(pattern, &name);
 
-            std::io::copy(&mut response, &mut dst_file)?;
+                // Skip "directory" placeholder objects
+                if rel.is_empty() || rel.ends_with('/') {
+                    continue;
+                }
 
-            Ok(())
+                let path = local_path(dst, &name, rel)?;
+                downloads.push((name, md, path));
+            }
+
+            fs::create_dir_all(dst)
+                .with_context(|| format!("failed to create '{}'", dst.display()))?;
+
+            download_all(ctx, bucket, downloads, &args.opts, args.jobs).await
+        }
+        (DataPath::Gs(src), DataPath::Gs(dst)) => {
+            if args.recursive {
+                rewrite_dir(ctx, src, dst, &args.opts, args.jobs).await
+            } else {
+                copy_object(
+                    ctx,
+                    (
+                        src.bucket(),
+                        src.object()
+                            .context("must provide a full object name to copy from")?,
+                    ),
+                    src.generation(),
+                    (
+                        dst.bucket(),
+                        dst.object()
+                            .context("must provide a full object name to copy to")?,
+                    ),
+                    &args.opts,
+                )
+                .await
+            }
+        }
+        (DataPath::GsPattern { bucket, pattern }, DataPath::Gs(dst)) => {
+            let mut copies = Vec::new();
+            for md in wildcard::expand_gcs(ctx, bucket, pattern).await? {
+                let name = md.name.context("object metadata did not contain a name")?;
+                let rel = wildcard::relative_name(pattern, &name);
+
+                // Skip "directory" placeholder objects
+                if rel.is_empty() || rel.ends_with('/') {
+                    continue;
+                }
+
+                let dst_object = join_object_name(dst.object(), Path::new(rel))?;
+                copies.push((name, dst_object));
+            }
+
+            rewrite_all(ctx, bucket, dst.bucket(), copies, &args.opts, args.jobs).await
+        }
+        (DataPath::Stdio, DataPath::Gs(dst)) => {
+            upload_stdin(
+                ctx,
+                dst.bucket(),
+                dst.object()
+                    .context("must provide a full object name to copy to")?,
+                &args.opts,
+            )
+            .await
+        }
+        (DataPath::Gs(src), DataPath::Stdio) => {
+            let object = src
+                .object()
+                .context("must provide a full object name to copy from")?;
+            let dl_req = encryption::with_key(
+                ctx.obj.download(
+                    &(src.bucket(), object),
+                    Some(objects::DownloadObjectOptional {
+                        generation: src.generation(),
+                        ..Default::default()
+                    }),
+                )?,
+                args.opts.encryption.decryption_key(),
+            );
+            let response = util::execute_stream(ctx, dl_req).await?;
+
+            write_response(
+                ctx,
+                &format!("gs://{}/{object}", src.bucket()),
+                response,
+                std::io::stdout(),
+                None,
+            )
+            .await
+            .context("failed to write to stdout")
+        }
+        (DataPath::GsPattern { .. }, DataPath::Stdio) => {
+            anyhow::bail!("use `gcs cat` to write multiple objects to stdout")
         }
         _ => unreachable!(),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn maps_local_paths() {
+        let dir = Path::new("dst");
+
+        assert_eq!(
+            local_path(dir, "prefix/a/b.txt", "a/b.txt").unwrap(),
+            dir.join("a").join("b.txt")
+        );
+
+        for rel in ["", "a//b", "./a", "a/../../b", "/a", "a/"] {
+            assert!(local_path(dir, rel, rel).is_err(), "{rel}");
+        }
+
+        // Backslashes and drive prefixes are only separators on Windows
+        for rel in ["a\\..\\..\\b", "C:", "C:\\b", "a/file:stream"] {
+            assert_eq!(local_path(dir, rel, rel).is_err(), cfg!(windows), "{rel}");
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn follows_symlinks() {
+        let root = tempfile::tempdir().unwrap();
+        let linked = tempfile::tempdir().unwrap();
+
+        let src = root.path().join("src");
+        fs::create_dir(&src).unwrap();
+        fs::write(src.join("file.txt"), "file").unwrap();
+        fs::write(linked.path().join("linked.txt"), "linked").unwrap();
+
+        let link = |target: &Path, name: &str| std::os::unix::fs::symlink(target, src.join(name));
+        link(linked.path(), "dir").unwrap();
+        link(&linked.path().join("linked.txt"), "file-link.txt").unwrap();
+        link(&root.path().join("missing"), "broken").unwrap();
+        link(&src, "loop").unwrap();
+
+        let mut files: Vec<_> = walk_files(
+            walkdir::WalkDir::new(&src),
+            &super::super::progress::Progress::new(),
+        )
+        .unwrap()
+        .into_iter()
+        .map(|entry| entry.path().strip_prefix(&src).unwrap().to_owned())
+        .collect();
+        files.sort();
+
+        assert_eq!(
+            files,
+            [
+                PathBuf::from("dir/linked.txt"),
+                PathBuf::from("file-link.txt"),
+                PathBuf::from("file.txt"),
+            ]
+        );
+    }
+}
@@ -0,0 +1,118 @@
+//! Customer-supplied (CSEK) and customer-managed (CMEK) encryption keys, see
+//! <https://cloud.google.com/storage/docs/encryption>
+
+use tame_gcs::http::{self, HeaderValue};
+
+/// The only algorithm GCS supports for customer-supplied encryption keys
+const ALGORITHM: &str = "AES256";
+
+/// A base64 encoded AES-256 customer-supplied encryption key, which must be
+/// sent, along with its SHA-256 hash, with every request that reads or writes
+/// the data of an object encrypted with it
+#[derive(Clone)]
+pub struct Key {
+    key: HeaderValue,
+    sha256: HeaderValue,
+}
+
+impl std::str::FromStr for Key {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        use base64::Engine;
+
+        let engine = &base64::engine::general_purpose::STANDARD;
+        let s = s.trim();
+
+        let key = engine
+            .decode(s)
+            .map_err(|err| format!("encryption key isn't valid base64 {err}"))?;
+        if key.len() != 32 {
+            return Err(format!(
+                "encryption key must be 32 bytes, but was {} bytes",
+                key.len()
+            ));
+        }
+
+        let sha256 = engine.encode(ring::digest::digest(&ring::digest::SHA256, &key));
+
+        let mut key = HeaderValue::try_from(s).map_err(|err| err.to_string())?;
+        key.set_sensitive(true);
+
+        Ok(Self {
+            key,
+            sha256: HeaderValue::try_from(sha256).map_err(|err| err.to_string())?,
+        })
+    }
+}
+
+impl Key {
+    /// Adds the headers for reading or writing an object encrypted with the key
+    #[inline]
+    pub fn apply<B>(&self, req: &mut http::Request<B>) {
+        self.insert(req.headers_mut(), "x-goog-encryption");
+    }
+
+    /// Adds the headers for reading the source object of a rewrite, which is
+    /// encrypted with the key
+    #[inline]
+    pub fn apply_copy_source<B>(&self, req: &mut http::Request<B>) {
+        self.insert(req.headers_mut(), "x-goog-copy-source-encryption");
+    }
+
+    fn insert(&self, headers: &mut http::HeaderMap, prefix: &str) {
+        let name = |suffix: &str| {
+            http::header::HeaderName::try_from(format!("{prefix}-{suffix}"))
+                .expect("encryption header names are valid")
+        };
+
+        headers.insert(name("algorithm"), HeaderValue::from_static(ALGORITHM));
+        headers.insert(name("key"), self.key.clone());
+        headers.insert(name("key-sha256"), self.sha256.clone());
+    }
+}
+
+/// Adds the headers for the key to the request, if there is one
+#[inline]
+pub fn with_key<B>(mut req: http::Request<B>, key: Option<&Key>) -> http::Request<B> {
+    if let Some(key) = key {
+        key.apply(&mut req);
+    }
+
+    req
+}
+
+/// The keys used to encrypt the objects written by a command, and decrypt the
+/// objects read by it
+#[derive(clap::Parser, Clone, Default)]
+pub struct Keys {
+    /// A base64 encoded AES-256 customer-supplied encryption key (CSEK) that
+    /// uploaded or copied objects are encrypted with
+    #[clap(long, conflicts_with = "kms_key")]
+    pub encryption_key: Option<Key>,
+    /// The Cloud KMS key, eg. `projects/<project>/locations/<location>/keyRings/<ring>/cryptoKeys/<key>`,
+    /// that uploaded or copied objects are encrypted with (CMEK), instead of
+    /// the bucket's default key
+    #[clap(long)]
+    pub kms_key: Option<String>,
+    /// A base64 encoded AES-256 customer-supplied encryption key (CSEK) that
+    /// source objects are encrypted with. This is never inferred from
+    /// `--encryption-key`, as GCS rejects keys for objects that aren't
+    /// encrypted with one
+    #[clap(long)]
+    pub decryption_key: Option<Key>,
+}
+
+impl Keys {
+    /// The key that objects written by the command are encrypted with
+    #[inline]
+    pub fn encryption_key(&self) -> Option<&Key> {
+        self.encryption_key.as_ref()
+    }
+
+    /// The key that objects read by the command are encrypted with
+    #[inline]
+    pub fn decryption_key(&self) -> Option<&Key> {
+        self.decryption_key.as_ref()
+    }
+}
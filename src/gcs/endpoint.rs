@@ -0,0 +1,62 @@
+use tame_gcs::http::{self, uri};
+
+/// A GCS compatible endpoint, such as a local emulator, that requests are sent
+/// to instead of GCS itself
+#[derive(Clone, Debug)]
+pub struct Endpoint {
+    scheme: uri::Scheme,
+    authority: uri::Authority,
+}
+
+impl std::str::FromStr for Endpoint {
+    type Err = String;
+
+    /// Parses an endpoint such as `http://localhost:4443`, defaulting to `http`
+    /// if no scheme is specified, as is the case for eg. `STORAGE_EMULATOR_HOST`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let uri: http::Uri = if s.contains("://") {
+            s.parse()
+        } else {
+            format!("http://{s}").parse()
+        }
+        .map_err(|err| format!("`{s}` isn't a valid endpoint {err}"))?;
+
+        let parts = uri.into_parts();
+
+        if parts
+            .path_and_query
+            .as_ref()
+            .map_or(false, |pq| pq.as_str() != "/")
+        {
+            return Err(format!("`{s}` must not contain a path"));
+        }
+
+        Ok(Self {
+            scheme: parts.scheme.ok_or_else(|| format!("`{s}` has no scheme"))?,
+            authority: parts
+                .authority
+                .ok_or_else(|| format!("`{s}` has no host"))?,
+        })
+    }
+}
+
+impl Endpoint {
+    /// Creates the object API that builds requests for this endpoint
+    #[inline]
+    pub fn object(&self) -> tame_gcs::objects::Object {
+        tame_gcs::objects::Object::with_authority(self.authority.clone())
+    }
+
+    /// tame-gcs always uses `https`, so requests to this endpoint need their
+    /// scheme rewritten for endpoints that don't use TLS, which is typical for
+    /// emulators
+    pub fn rewrite(&self, uri: http::Uri) -> anyhow::Result<http::Uri> {
+        if uri.authority() != Some(&self.authority) || uri.scheme() == Some(&self.scheme) {
+            return Ok(uri);
+        }
+
+        let mut parts = uri.into_parts();
+        parts.scheme = Some(self.scheme.clone());
+        Ok(http::Uri::from_parts(parts)?)
+    }
+}
@@ -0,0 +1,503 @@
+use super::{buckets, mb, retry, util};
+use anyhow::Context as _;
+use serde_json::{Map, Value};
+use tame_gcs::{http, BucketName};
+
+/// Gets or modifies the IAM policy of a bucket
+#[derive(clap::Subcommand)]
+pub enum Args {
+    /// Prints the IAM policy of a bucket as JSON
+    Get {
+        /// The gs: URL of the bucket
+        url: String,
+    },
+    /// Adds members to, or removes members from, roles in the IAM policy of a
+    /// bucket
+    Ch(ChArgs),
+    /// Replaces the IAM policy of a bucket with the policy in a JSON file. If
+    /// the policy contains the `etag` from `gcs iam get`, the policy is only
+    /// replaced if it hasn't been modified since
+    Set {
+        /// The gs: URL of the bucket
+        url: String,
+        /// The path of the JSON file, or `-` to read it from stdin
+        file: String,
+    },
+}
+
+#[derive(clap::Parser)]
+pub struct ChArgs {
+    /// Removes a member from a role, eg. `-d user:x@y.com:objectViewer`, or
+    /// from every role if the role is omitted, eg. `-d user:x@y.com`
+    #[clap(short = 'd', long = "delete")]
+    delete: Vec<String>,
+    /// The gs: URL of the bucket
+    url: String,
+    /// One or more `<member>:<role>` bindings to add, eg.
+    /// `user:x@y.com:objectViewer` or `allUsers:objectViewer`. Roles without a
+    /// `roles/` prefix are treated as predefined storage roles, ie.
+    /// `objectViewer` is `roles/storage.objectViewer`
+    bindings: Vec<String>,
+}
+
+/// The number of times a read-modify-write of a policy is attempted before
+/// giving up due to concurrent modifications
+const MAX_ATTEMPTS: usize = 5;
+
+#[inline]
+fn iam_url(ctx: &util::RequestContext, bucket: &BucketName<'_>) -> anyhow::Result<String> {
+    Ok(format!("{}/iam", util::bucket_url(ctx, Some(bucket))?))
+}
+
+async fn get_policy(
+    ctx: &util::RequestContext,
+    bucket: &BucketName<'_>,
+) -> anyhow::Result<Map<String, Value>> {
+    let get_req = http::Request::builder()
+        .method(http::Method::GET)
+        // Version 3 is required to retrieve bindings that have conditions
+        .uri(format!(
+            "{}?optionsRequestedPolicyVersion=3",
+            iam_url(ctx, bucket)?
+        ))
+        .body(std::io::empty())?;
+
+    util::execute_json(ctx, get_req)
+        .await
+        .with_context(|| format!("failed to retrieve IAM policy for 'gs://{bucket}'"))
+}
+
+/// Replaces the policy. GCS rejects the policy with a precondition failure if
+/// it contains an etag that doesn't match the current policy's, which is also
+/// the only case in which the request can safely be retried
+async fn set_policy(
+    ctx: &util::RequestContext,
+    bucket: &BucketName<'_>,
+    policy: &Map<String, Value>,
+) -> anyhow::Result<()> {
+    let set_req = http::Request::builder()
+        .method(http::Method::PUT)
+        .uri(iam_url(ctx, bucket)?)
+        .body(std::io::empty())?;
+    let mut set_req = util::with_json_body(set_req, &Value::Object(policy.clone()))?;
+    set_req
+        .extensions_mut()
+        .insert(retry::Idempotent(policy.contains_key("etag")));
+
+    match util::execute_json(ctx, set_req).await {
+        Ok(_) => Ok(()),
+        // If an earlier attempt was applied but its response was lost, the
+        // retry fails as the etag has changed, which isn't a conflict if the
+        // policy is now the one we set
+        Err(err) if util::is_precondition_failure(&err) => {
+            let current = get_policy(ctx, bucket).await?;
+
+            if same_bindings(&current, policy) {
+                Ok(())
+            } else {
+                Err(err)
+            }
+        }
+        Err(err) => Err(err),
+    }
+}
+
+/// Checks if two policies have the same bindings, ignoring the order of the
+/// bindings and of their members, which GCS doesn't preserve
+fn same_bindings(a: &Map<String, Value>, b: &Map<String, Value>) -> bool {
+    fn normalize(policy: &Map<String, Value>) -> Vec<(String, String, Vec<String>)> {
+        let mut bindings: Vec<_> = policy
+            .get("bindings")
+            .and_then(|b| b.as_array())
+            .into_iter()
+            .flatten()
+            .map(|binding| {
+                let mut members: Vec<_> = binding
+                    .get("members")
+                    .and_then(|m| m.as_array())
+                    .into_iter()
+                    .flatten()
+                    .map(|m| m.to_string())
+                    .collect();
+                members.sort();
+
+                (
+                    binding
+                        .get("role")
+                        .map(|r| r.to_string())
+                        .unwrap_or_default(),
+                    binding
+                        .get("condition")
+                        .map(|c| c.to_string())
+                        .unwrap_or_default(),
+                    members,
+                )
+            })
+            .collect();
+        bindings.sort();
+        bindings
+    }
+
+    normalize(a) == normalize(b)
+}
+
+/// The types of members that are prefixed to their identifier, eg. the `user`
+/// in `user:x@y.com`
+const MEMBER_TYPES: &[&str] = &[
+    "user",
+    "group",
+    "serviceAccount",
+    "domain",
+    "principal",
+    "principalSet",
+    "projectOwner",
+    "projectEditor",
+    "projectViewer",
+    "deleted",
+];
+
+/// Splits a `<member>:<role>` binding, where the member itself may contain a
+/// `:`, eg. `user:x@y.com:objectViewer`. A binding whose role was forgotten,
+/// eg. `user:x@y.com`, is rejected rather than being split after the type
+fn parse_binding(binding: &str) -> anyhow::Result<(&str, String)> {
+    let (member, role) = binding
+        .rsplit_once(':')
+        .with_context(|| format!("'{binding}' is not of the form <member>:<role>"))?;
+
+    anyhow::ensure!(
+        !member.is_empty() && !role.is_empty(),
+        "'{binding}' is not of the form <member>:<role>"
+    );
+    anyhow::ensure!(
+        !MEMBER_TYPES.contains(&member),
+        "'{binding}' does not specify a role, expected <member>:<role>"
+    );
+
+    Ok((member, expand_role(role)))
+}
+
+#[inline]
+fn expand_role(role: &str) -> String {
+    if role.starts_with("roles/") || role.starts_with("projects/") {
+        role.to_owned()
+    } else {
+        format!("roles/storage.{role}")
+    }
+}
+
+/// Splits a removal into the member and, optionally, the role. Members such
+/// as `allUsers` don't contain a `:`, so the last `:` only separates a role if
+/// it isn't the one after the member's type, eg. `user:x@y.com`
+fn parse_removal(removal: &str) -> (&str, Option<String>) {
+    match removal.rsplit_once(':') {
+        Some((member, role)) if !MEMBER_TYPES.contains(&member) => {
+            (member, Some(expand_role(role)))
+        }
+        _ => (removal, None),
+    }
+}
+
+/// Applies the changes to the policy's bindings, ignoring bindings that have a
+/// condition, as those are only ever modified via `gcs iam set`
+fn apply_changes(
+    policy: &mut Map<String, Value>,
+    add: &[(&str, String)],
+    remove: &[(&str, Option<String>)],
+) -> anyhow::Result<()> {
+    let bindings = policy
+        .entry("bindings")
+        .or_insert_with(|| Value::Array(Vec::new()))
+        .as_array_mut()
+        .context("policy bindings is not an array")?;
+
+    for (member, role) in add {
+        let binding = bindings.iter_mut().find(|binding| {
+            binding.get("condition").is_none()
+                && binding.get("role").and_then(|r| r.as_str()) == Some(role.as_str())
+        });
+
+        match binding {
+            Some(binding) => {
+                let members = binding
+                    .as_object_mut()
+                    .context("policy binding is not an object")?
+                    .entry("members")
+                    .or_insert_with(|| Value::Array(Vec::new()))
+                    .as_array_mut()
+                    .context("binding members is not an array")?;
+
+                if !members.iter().any(|m| m.as_str() == Some(member)) {
+                    members.push(Value::String((*member).to_owned()));
+                }
+            }
+            None => {
+                bindings.push(serde_json::json!({
+                    "role": role,
+                    "members": [member],
+                }));
+            }
+        }
+    }
+
+    for (member, role) in remove {
+        for binding in bindings.iter_mut() {
+            if binding.get("condition").is_some() {
+                continue;
+            }
+
+            if let Some(role) = role {
+                if binding.get("role").and_then(|r| r.as_str()) != Some(role.as_str()) {
+                    continue;
+                }
+            }
+
+            if let Some(Value::Array(members)) = binding.get_mut("members") {
+                members.retain(|m| m.as_str() != Some(member));
+            }
+        }
+    }
+
+    // GCS rejects bindings without any members
+    bindings.retain(|binding| {
+        binding
+            .get("members")
+            .and_then(|m| m.as_array())
+            .map_or(false, |m| !m.is_empty())
+    });
+
+    Ok(())
+}
+
+async fn ch(ctx: &util::RequestContext, args: ChArgs) -> anyhow::Result<()> {
+    let bucket = mb::parse_bucket_url(&args.url)?;
+
+    let add = args
+        .bindings
+        .iter()
+        .map(|binding| parse_binding(binding))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    let remove: Vec<_> = args.delete.iter().map(|r| parse_removal(r)).collect();
+
+    anyhow::ensure!(
+        !add.is_empty() || !remove.is_empty(),
+        "no IAM changes were specified"
+    );
+
+    // The policy we retrieve contains an etag, so if another change is made
+    // to the policy between our read and write, the write fails and we start
+    // over with the new policy rather than clobbering the other change
+    for _ in 0..MAX_ATTEMPTS {
+        let mut policy = get_policy(ctx, &bucket).await?;
+        apply_changes(&mut policy, &add, &remove)?;
+
+        match set_policy(ctx, &bucket, &policy).await {
+            Ok(()) => {
+                println!("Updated IAM policy for gs://{bucket}");
+                return Ok(());
+            }
+            Err(err) if util::is_precondition_failure(&err) => continue,
+            Err(err) => {
+                return Err(err)
+                    .with_context(|| format!("failed to set IAM policy for 'gs://{bucket}'"))
+            }
+        }
+    }
+
+    anyhow::bail!(
+        "failed to update IAM policy for 'gs://{bucket}', it was concurrently modified {MAX_ATTEMPTS} times"
+    )
+}
+
+pub async fn run(ctx: &util::RequestContext, args: Args) -> anyhow::Result<()> {
+    match args {
+        Args::Get { url } => {
+            let bucket = mb::parse_bucket_url(&url)?;
+            let policy = get_policy(ctx, &bucket).await?;
+            println!("{}", serde_json::to_string_pretty(&policy)?);
+        }
+        Args::Ch(args) => ch(ctx, args).await?,
+        Args::Set { url, file } => {
+            let bucket = mb::parse_bucket_url(&url)?;
+            let policy: Map<String, Value> = buckets::read_config(&file)?;
+
+            anyhow::ensure!(
+                matches!(policy.get("bindings"), Some(Value::Array(_))),
+                "'{file}' does not contain a bindings array"
+            );
+
+            set_policy(ctx, &bucket, &policy).await.map_err(|err| {
+                if util::is_precondition_failure(&err) {
+                    err.context(format!(
+                        "the IAM policy for 'gs://{bucket}' was modified since '{file}' was retrieved"
+                    ))
+                } else {
+                    err.context(format!("failed to set IAM policy for 'gs://{bucket}'"))
+                }
+            })?;
+
+            println!("Set IAM policy for gs://{bucket}");
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn policy(policy: Value) -> Map<String, Value> {
+        match policy {
+            Value::Object(map) => map,
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn parses_removals() {
+        assert_eq!(parse_removal("user:x@y.com"), ("user:x@y.com", None));
+        assert_eq!(parse_removal("allUsers"), ("allUsers", None));
+        assert_eq!(
+            parse_removal("user:x@y.com:objectViewer"),
+            (
+                "user:x@y.com",
+                Some("roles/storage.objectViewer".to_owned())
+            )
+        );
+        assert_eq!(
+            parse_removal("allUsers:roles/storage.legacyBucketReader"),
+            (
+                "allUsers",
+                Some("roles/storage.legacyBucketReader".to_owned())
+            )
+        );
+    }
+
+    #[test]
+    fn parses_bindings() {
+        assert_eq!(
+            parse_binding("user:x@y.com:objectViewer").unwrap(),
+            ("user:x@y.com", "roles/storage.objectViewer".to_owned())
+        );
+        assert_eq!(
+            parse_binding("allUsers:roles/storage.legacyBucketReader").unwrap(),
+            ("allUsers", "roles/storage.legacyBucketReader".to_owned())
+        );
+
+        // The role was forgotten, so the "member" is only the type
+        assert!(parse_binding("user:x@y.com").is_err());
+        assert!(parse_binding("serviceAccount:sa@p.iam.gserviceaccount.com").is_err());
+        assert!(parse_binding("allUsers").is_err());
+        assert!(parse_binding("user:x@y.com:").is_err());
+        assert!(parse_binding(":objectViewer").is_err());
+    }
+
+    #[test]
+    fn adds_members() {
+        let mut p = policy(json!({
+            "bindings": [{ "role": "roles/storage.objectViewer", "members": ["user:a@y.com"] }],
+        }));
+
+        let add = [
+            parse_binding("user:b@y.com:objectViewer").unwrap(),
+            // Already a member
+            parse_binding("user:a@y.com:objectViewer").unwrap(),
+            parse_binding("allUsers:roles/storage.legacyBucketReader").unwrap(),
+        ];
+        apply_changes(&mut p, &add, &[]).unwrap();
+
+        assert_eq!(
+            p["bindings"],
+            json!([
+                { "role": "roles/storage.objectViewer", "members": ["user:a@y.com", "user:b@y.com"] },
+                { "role": "roles/storage.legacyBucketReader", "members": ["allUsers"] },
+            ])
+        );
+
+        // A policy without any bindings
+        let mut p = policy(json!({ "etag": "CAE=" }));
+        apply_changes(&mut p, &add[..1], &[]).unwrap();
+        assert_eq!(
+            p["bindings"],
+            json!([{ "role": "roles/storage.objectViewer", "members": ["user:b@y.com"] }])
+        );
+    }
+
+    #[test]
+    fn compares_bindings() {
+        let sent = policy(json!({
+            "etag": "CAE=",
+            "bindings": [
+                {"role": "roles/storage.objectViewer", "members": ["user:a@x.com", "allUsers"]},
+                {"role": "roles/storage.admin", "members": ["group:g@x.com"]},
+            ]
+        }));
+
+        // The policy after a lost response has a new etag, and GCS may have
+        // reordered the bindings and members
+        let current = policy(json!({
+            "etag": "CAI=",
+            "version": 1,
+            "bindings": [
+                {"role": "roles/storage.admin", "members": ["group:g@x.com"]},
+                {"role": "roles/storage.objectViewer", "members": ["allUsers", "user:a@x.com"]},
+            ]
+        }));
+        assert!(same_bindings(&sent, &current));
+
+        let concurrent = policy(json!({
+            "etag": "CAI=",
+            "bindings": [
+                {"role": "roles/storage.admin", "members": ["group:g@x.com", "user:b@x.com"]},
+                {"role": "roles/storage.objectViewer", "members": ["allUsers", "user:a@x.com"]},
+            ]
+        }));
+        assert!(!same_bindings(&sent, &concurrent));
+
+        let conditional = policy(json!({
+            "bindings": [
+                {"role": "roles/storage.admin", "members": ["group:g@x.com"], "condition": {"expression": "true"}},
+                {"role": "roles/storage.objectViewer", "members": ["allUsers", "user:a@x.com"]},
+            ]
+        }));
+        assert!(!same_bindings(&sent, &conditional));
+    }
+
+    #[test]
+    fn removes_members() {
+        let mut p = policy(json!({
+            "bindings": [
+                { "role": "roles/storage.objectViewer", "members": ["user:a@y.com", "user:b@y.com"] },
+                { "role": "roles/storage.admin", "members": ["user:a@y.com"] },
+                {
+                    "role": "roles/storage.objectViewer",
+                    "members": ["user:a@y.com"],
+                    "condition": { "title": "t", "expression": "true" },
+                },
+            ],
+        }));
+
+        // Only removed from the one role, the now empty binding being removed
+        apply_changes(&mut p, &[], &[parse_removal("user:a@y.com:admin")]).unwrap();
+        assert_eq!(p["bindings"].as_array().unwrap().len(), 2);
+        assert_eq!(
+            p["bindings"][0]["members"],
+            json!(["user:a@y.com", "user:b@y.com"])
+        );
+
+        // Removed from every role, except for the conditional binding
+        apply_changes(&mut p, &[], &[parse_removal("user:a@y.com")]).unwrap();
+        assert_eq!(
+            p["bindings"],
+            json!([
+                { "role": "roles/storage.objectViewer", "members": ["user:b@y.com"] },
+                {
+                    "role": "roles/storage.objectViewer",
+                    "members": ["user:a@y.com"],
+                    "condition": { "title": "t", "expression": "true" },
+                },
+            ])
+        );
+    }
+}
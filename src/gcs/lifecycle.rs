@@ -0,0 +1,231 @@
+use super::{buckets, mb, util};
+use serde::{Deserialize, Serialize};
+
+/// Gets or sets the lifecycle configuration of a bucket
+#[derive(clap::Subcommand)]
+pub enum Args {
+    /// Prints the lifecycle configuration of a bucket as JSON
+    Get {
+        /// The gs: URL of the bucket
+        url: String,
+    },
+    /// Replaces the lifecycle configuration of a bucket with the rules in a
+    /// JSON file, eg. `{"rule": [{"action": {"type": "Delete"}, "condition": {"age": 180}}]}`.
+    /// A configuration without any rules removes the lifecycle configuration
+    Set {
+        /// The gs: URL of the bucket
+        url: String,
+        /// The path of the JSON file, or `-` to read it from stdin
+        file: String,
+    },
+}
+
+#[derive(Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+struct Lifecycle {
+    #[serde(default)]
+    rule: Vec<Rule>,
+}
+
+#[derive(Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+struct Rule {
+    action: Action,
+    condition: Condition,
+}
+
+#[derive(Deserialize, Serialize, PartialEq)]
+enum ActionType {
+    Delete,
+    SetStorageClass,
+    AbortIncompleteMultipartUpload,
+}
+
+#[derive(Deserialize, Serialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+struct Action {
+    #[serde(rename = "type")]
+    kind: ActionType,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    storage_class: Option<String>,
+}
+
+#[derive(Deserialize, Serialize, Default, PartialEq)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+struct Condition {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    age: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    created_before: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    custom_time_before: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    days_since_custom_time: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    days_since_noncurrent_time: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    is_live: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    matches_prefix: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    matches_storage_class: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    matches_suffix: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    noncurrent_time_before: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    num_newer_versions: Option<u32>,
+}
+
+/// Checks the rules for mistakes that GCS would otherwise only report as an
+/// opaque "invalid argument"
+fn validate(lifecycle: &Lifecycle) -> anyhow::Result<()> {
+    for (i, rule) in lifecycle.rule.iter().enumerate() {
+        match (&rule.action.kind, &rule.action.storage_class) {
+            (ActionType::SetStorageClass, None) => {
+                anyhow::bail!("rule {i}: the SetStorageClass action requires a storageClass")
+            }
+            (ActionType::SetStorageClass, Some(_)) | (_, None) => {}
+            (_, Some(_)) => {
+                anyhow::bail!("rule {i}: only the SetStorageClass action can have a storageClass")
+            }
+        }
+
+        anyhow::ensure!(
+            rule.condition != Condition::default(),
+            "rule {i}: the condition must contain at least one field"
+        );
+
+        for date in [
+            &rule.condition.created_before,
+            &rule.condition.custom_time_before,
+            &rule.condition.noncurrent_time_before,
+        ]
+        .into_iter()
+        .flatten()
+        {
+            let is_date = date.len() == 10
+                && date.char_indices().all(|(i, c)| match i {
+                    4 | 7 => c == '-',
+                    _ => c.is_ascii_digit(),
+                });
+            anyhow::ensure!(is_date, "rule {i}: '{date}' is not a YYYY-MM-DD date");
+        }
+    }
+
+    Ok(())
+}
+
+/// The bucket patch that applies the configuration, a configuration without
+/// any rules removing the lifecycle configuration entirely
+fn patch(lifecycle: &Lifecycle) -> anyhow::Result<serde_json::Value> {
+    let lifecycle = if lifecycle.rule.is_empty() {
+        serde_json::Value::Null
+    } else {
+        serde_json::to_value(lifecycle)?
+    };
+
+    Ok(serde_json::json!({ "lifecycle": lifecycle }))
+}
+
+pub async fn run(ctx: &util::RequestContext, args: Args) -> anyhow::Result<()> {
+    match args {
+        Args::Get { url } => {
+            let bucket = mb::parse_bucket_url(&url)?;
+            let resource = buckets::get(ctx, &bucket, "lifecycle").await?;
+
+            match resource.get("lifecycle") {
+                Some(lifecycle) => println!("{}", serde_json::to_string_pretty(lifecycle)?),
+                None => println!("gs://{bucket} has no lifecycle configuration"),
+            }
+        }
+        Args::Set { url, file } => {
+            let bucket = mb::parse_bucket_url(&url)?;
+            let lifecycle: Lifecycle = buckets::read_config(&file)?;
+            validate(&lifecycle)?;
+
+            buckets::patch(ctx, &bucket, &patch(&lifecycle)?).await?;
+            println!("Set lifecycle configuration on gs://{bucket}");
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lifecycle(json: &str) -> anyhow::Result<()> {
+        validate(&serde_json::from_str(json)?)
+    }
+
+    #[test]
+    fn accepts_valid_rules() {
+        assert!(lifecycle("{}").is_ok());
+        assert!(lifecycle(r#"{"rule": []}"#).is_ok());
+        assert!(lifecycle(
+            r#"{"rule": [
+                {"action": {"type": "Delete"}, "condition": {"age": 180}},
+                {"action": {"type": "SetStorageClass", "storageClass": "COLDLINE"}, "condition": {"createdBefore": "2023-01-31"}},
+                {"action": {"type": "AbortIncompleteMultipartUpload"}, "condition": {"age": 7, "isLive": true}}
+            ]}"#
+        )
+        .is_ok());
+    }
+
+    #[test]
+    fn patches_rules() {
+        let json = serde_json::json!({"rule": [
+            {"action": {"type": "SetStorageClass", "storageClass": "COLDLINE"}, "condition": {"age": 30, "matchesPrefix": ["logs/"]}},
+        ]});
+
+        // Only the fields in the file are sent, with their original names
+        assert_eq!(
+            patch(&serde_json::from_value(json.clone()).unwrap()).unwrap(),
+            serde_json::json!({ "lifecycle": json })
+        );
+        assert_eq!(
+            patch(&serde_json::from_str(r#"{"rule": []}"#).unwrap()).unwrap(),
+            serde_json::json!({ "lifecycle": null })
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_rules() {
+        // Storage class is required for, and only allowed for, SetStorageClass
+        assert!(lifecycle(
+            r#"{"rule": [{"action": {"type": "SetStorageClass"}, "condition": {"age": 1}}]}"#
+        )
+        .is_err());
+        assert!(lifecycle(
+            r#"{"rule": [{"action": {"type": "Delete", "storageClass": "COLDLINE"}, "condition": {"age": 1}}]}"#
+        )
+        .is_err());
+
+        assert!(
+            lifecycle(r#"{"rule": [{"action": {"type": "Delete"}, "condition": {}}]}"#).is_err()
+        );
+
+        for date in [
+            "2023-1-31",
+            "2023/01/31",
+            "2023-01-31T00:00:00Z",
+            "yesterday",
+        ] {
+            let json = format!(
+                r#"{{"rule": [{{"action": {{"type": "Delete"}}, "condition": {{"customTimeBefore": "{date}"}}}}]}}"#
+            );
+            assert!(lifecycle(&json).is_err(), "{date}");
+        }
+
+        assert!(lifecycle(
+            r#"{"rule": [{"action": {"type": "Archive"}, "condition": {"age": 1}}]}"#
+        )
+        .is_err());
+        assert!(lifecycle(
+            r#"{"rule": [{"action": {"type": "Delete"}, "condition": {"ageDays": 1}}]}"#
+        )
+        .is_err());
+    }
+}
@@ -0,0 +1,211 @@
+use super::{util, wildcard};
+use anyhow::Context as _;
+use tame_gcs::{
+    http,
+    objects::{self, Metadata},
+    BucketName,
+};
+
+/// Lists the objects and "directories" beneath a prefix
+#[derive(clap::Parser)]
+pub struct Args {
+    /// Prints the size, last update time and generation of each object, as
+    /// well as the total number of objects and bytes
+    #[clap(short = 'l', long)]
+    long: bool,
+    /// Lists every object beneath the prefix, rather than only the objects and
+    /// "directories" immediately beneath it
+    #[clap(short = 'r', long)]
+    recursive: bool,
+    /// Prints the listing as a single JSON document
+    #[clap(long)]
+    json: bool,
+    /// A gs: URL of the bucket or prefix to list. Supports `*`, `**` and `?`
+    /// wildcards, in which case every matching object is listed
+    url: String,
+}
+
+#[derive(serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+struct JsonObject {
+    url: String,
+    name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    size: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    updated: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    generation: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    metageneration: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    content_type: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    storage_class: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    crc32c: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    md5_hash: Option<String>,
+}
+
+#[derive(serde::Serialize)]
+struct JsonListing {
+    objects: Vec<JsonObject>,
+    prefixes: Vec<String>,
+}
+
+#[inline]
+fn format_timestamp(ts: Option<objects::Timestamp>) -> Option<String> {
+    ts.and_then(|ts| {
+        ts.format(&time::format_description::well_known::Rfc3339)
+            .ok()
+    })
+}
+
+pub async fn run(ctx: &util::RequestContext, args: Args) -> anyhow::Result<()> {
+    let (bucket, listing) = if wildcard::has_wildcard(&args.url) {
+        let (bucket, pattern) = wildcard::parse_gs_pattern(&args.url)?;
+        let objects = wildcard::expand_gcs(ctx, &bucket, &pattern).await?;
+
+        (
+            bucket,
+            util::Listing {
+                objects,
+                prefixes: Vec::new(),
+            },
+        )
+    } else {
+        let url = url::Url::parse(&args.url)?;
+        let gs_url = util::gs_url_to_object_id(&url)?;
+        anyhow::ensure!(
+            gs_url.generation().is_none(),
+            "'{}' specifies a generation, which is not supported by ls",
+            args.url
+        );
+
+        // Like gsutil, an object whose name exactly matches the url is listed
+        // alongside the contents of the "directory" of the same name, as GCS
+        // allows both to exist at the same time
+        let exact = match gs_url.object() {
+            Some(obn) if !AsRef::<str>::as_ref(obn).ends_with('/') => {
+                let get_req = ctx.obj.get(&(gs_url.bucket(), obn), None)?;
+                let response = util::execute_raw(ctx, get_req).await?;
+
+                if response.status() == http::StatusCode::NOT_FOUND {
+                    None
+                } else if response.status().is_success() {
+                    use tame_gcs::ApiResponse;
+                    Some(objects::GetObjectResponse::try_from_parts(response)?.metadata)
+                } else {
+                    return Err(util::api_error(response));
+                }
+            }
+            _ => None,
+        };
+
+        let prefix = gs_url.object().map(|obn| {
+            let mut prefix = obn.to_string();
+            if !prefix.ends_with('/') {
+                prefix.push('/');
+            }
+            prefix
+        });
+
+        let mut listing = util::list(
+            ctx,
+            gs_url.bucket(),
+            prefix.as_deref(),
+            (!args.recursive).then_some("/"),
+        )
+        .await?;
+
+        if let Some(exact) = exact {
+            listing.objects.insert(0, exact);
+        }
+
+        anyhow::ensure!(
+            gs_url.object().is_none()
+                || !listing.objects.is_empty()
+                || !listing.prefixes.is_empty(),
+            "'{}' matched no objects",
+            args.url
+        );
+
+        (BucketName::try_from(gs_url.bucket().to_string())?, listing)
+    };
+
+    let object_url = |md: &Metadata| {
+        format!(
+            "gs://{}/{}",
+            md.bucket.as_deref().unwrap_or_default(),
+            md.name.as_deref().unwrap_or_default()
+        )
+    };
+
+    let prefix_url = |prefix: &str| format!("gs://{bucket}/{prefix}");
+
+    if args.json {
+        let json = JsonListing {
+            objects: listing
+                .objects
+                .into_iter()
+                .map(|md| JsonObject {
+                    url: object_url(&md),
+                    name: md.name.unwrap_or_default(),
+                    size: md.size,
+                    updated: format_timestamp(md.updated),
+                    generation: md.generation,
+                    metageneration: md.metageneration,
+                    content_type: md.content_type,
+                    storage_class: md.storage_class.map(|sc| sc.to_string()),
+                    crc32c: md.crc32c,
+                    md5_hash: md.md5_hash,
+                })
+                .collect(),
+            prefixes: listing.prefixes.iter().map(|p| prefix_url(p)).collect(),
+        };
+
+        println!(
+            "{}",
+            serde_json::to_string_pretty(&json).context("failed to serialize listing")?
+        );
+
+        return Ok(());
+    }
+
+    if args.long {
+        let mut total_size = 0;
+
+        for md in &listing.objects {
+            let size = md.size.unwrap_or_default();
+            total_size += size;
+
+            println!(
+                "{size:>12}  {:<25}  {:>16}  {}",
+                format_timestamp(md.updated).unwrap_or_default(),
+                md.generation.unwrap_or_default(),
+                object_url(md)
+            );
+        }
+
+        for prefix in &listing.prefixes {
+            println!("{:>59}  {}", "", prefix_url(prefix));
+        }
+
+        println!(
+            "TOTAL: {} objects, {total_size} bytes ({})",
+            listing.objects.len(),
+            crate::bytes_to_human(total_size)
+        );
+    } else {
+        for md in &listing.objects {
+            println!("{}", object_url(md));
+        }
+
+        for prefix in &listing.prefixes {
+            println!("{}", prefix_url(prefix));
+        }
+    }
+
+    Ok(())
+}
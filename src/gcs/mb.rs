@@ -0,0 +1,83 @@
+use super::{setmeta::StorageClass, util};
+use anyhow::Context as _;
+use tame_gcs::http;
+
+/// Creates one or more buckets
+#[derive(clap::Parser)]
+pub struct Args {
+    /// The project the buckets are created in
+    #[clap(short = 'p', long, env = "GOOGLE_CLOUD_PROJECT")]
+    project: String,
+    /// The location of the buckets, eg. `US`, `EU` or `europe-north1`. Defaults
+    /// to `US`
+    #[clap(short = 'l', long)]
+    location: Option<String>,
+    /// The default storage class of objects in the buckets
+    #[clap(short = 'c', long)]
+    storage_class: Option<StorageClass>,
+    /// Enables uniform bucket-level access, which disables object ACLs so that
+    /// access is controlled solely by IAM
+    #[clap(short = 'b', long)]
+    uniform_bucket_level_access: bool,
+    /// One or more gs: URLs of the buckets to create, eg. `gs://bucket`
+    #[clap(required = true)]
+    urls: Vec<String>,
+}
+
+/// Parses a `gs://<bucket>` url, failing if it also contains an object
+pub(super) fn parse_bucket_url(url: &str) -> anyhow::Result<tame_gcs::BucketName<'static>> {
+    let parsed = url::Url::parse(url)?;
+    let gs_url = util::gs_url_to_object_id(&parsed)?;
+
+    anyhow::ensure!(
+        gs_url.object().is_none(),
+        "'{url}' is an object, not a bucket"
+    );
+    anyhow::ensure!(
+        gs_url.generation().is_none(),
+        "'{url}' specifies a generation, which buckets don't have"
+    );
+
+    Ok(tame_gcs::BucketName::try_from(gs_url.bucket().to_string())?)
+}
+
+pub async fn run(ctx: &util::RequestContext, args: Args) -> anyhow::Result<()> {
+    for url in &args.urls {
+        let bucket = parse_bucket_url(url)?;
+
+        let mut resource = serde_json::json!({ "name": bucket.to_string() });
+        if let Some(location) = &args.location {
+            resource["location"] = location.as_str().into();
+        }
+        if let Some(storage_class) = args.storage_class {
+            resource["storageClass"] = storage_class.as_str().into();
+        }
+        if args.uniform_bucket_level_access {
+            resource["iamConfiguration"] = serde_json::json!({
+                "uniformBucketLevelAccess": { "enabled": true },
+            });
+        }
+
+        let uri = format!(
+            "{}?{}",
+            util::bucket_url(ctx, None)?,
+            url::form_urlencoded::Serializer::new(String::new())
+                .append_pair("project", &args.project)
+                .finish()
+        );
+
+        let insert_req = http::Request::builder()
+            .method(http::Method::POST)
+            .uri(uri)
+            .body(std::io::empty())?;
+        let insert_req = util::with_json_body(insert_req, &resource)?;
+
+        util::execute_json(ctx, insert_req)
+            .await
+            .with_context(|| format!("failed to create bucket 'gs://{bucket}'"))?;
+
+        println!("Created gs://{bucket}");
+    }
+
+    Ok(())
+}
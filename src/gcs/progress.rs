@@ -0,0 +1,396 @@
+//! Progress reporting for transfers. When stdout is a terminal, the progress
+//! of each active transfer, and of all transfers combined, is displayed as a
+//! block of bars that is redrawn in place. Otherwise, eg. in CI logs or when
+//! output is redirected to a file, plain progress lines are printed
+//! periodically instead.
+//!
+//! Progress is always written to stderr so that it doesn't interfere with
+//! output written to stdout, eg. `gcs cat`, which is why the bars also require
+//! stderr to be a terminal
+
+use crate::bytes_to_human;
+use std::{
+    fmt::Write as _,
+    io::Write as _,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+/// The minimum time between redraws of the progress block on a terminal
+const TTY_INTERVAL: Duration = Duration::from_millis(100);
+/// The minimum time between progress lines when not on a terminal
+const PLAIN_INTERVAL: Duration = Duration::from_secs(10);
+/// The maximum number of individual transfers displayed at once on a terminal
+const MAX_DISPLAYED: usize = 8;
+/// The width of the progress bars, in characters
+const BAR_WIDTH: usize = 30;
+
+struct TransferState {
+    id: usize,
+    name: String,
+    total: Option<u64>,
+    done: u64,
+    started: Instant,
+}
+
+struct State {
+    /// The transfers that are still in progress
+    active: Vec<TransferState>,
+    next_id: usize,
+    /// The combined total of every transfer whose size is known
+    total: u64,
+    done: u64,
+    files: usize,
+    finished_files: usize,
+    /// The time of the first transfer, rather than when the progress was
+    /// created, so that throughput isn't skewed by eg. listing
+    started: Option<Instant>,
+    last_render: Option<Instant>,
+    /// Whether any progress has been output, in which case a summary is
+    /// printed once every transfer is finished
+    shown: bool,
+    /// The number of lines in the progress block currently on the terminal
+    drawn: usize,
+}
+
+struct Inner {
+    tty: bool,
+    state: Mutex<State>,
+}
+
+/// Reports the progress of every transfer performed by a command
+#[derive(Clone)]
+pub struct Progress {
+    inner: Arc<Inner>,
+}
+
+impl Default for Progress {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Progress {
+    pub fn new() -> Self {
+        use is_terminal::IsTerminal;
+
+        Self {
+            inner: Arc::new(Inner {
+                tty: std::io::stdout().is_terminal() && std::io::stderr().is_terminal(),
+                state: Mutex::new(State {
+                    active: Vec::new(),
+                    next_id: 0,
+                    total: 0,
+                    done: 0,
+                    files: 0,
+                    finished_files: 0,
+                    started: None,
+                    last_render: None,
+                    shown: false,
+                    drawn: 0,
+                }),
+            }),
+        }
+    }
+
+    /// Starts tracking a new transfer, which is finished when dropped
+    pub fn transfer(&self, name: impl Into<String>, total: Option<u64>) -> Transfer {
+        let mut state = self.inner.lock();
+
+        let id = state.next_id;
+        state.next_id += 1;
+        state.files += 1;
+        state.total += total.unwrap_or_default();
+        state.started.get_or_insert_with(Instant::now);
+        state.active.push(TransferState {
+            id,
+            name: name.into(),
+            total,
+            done: 0,
+            started: Instant::now(),
+        });
+
+        self.inner.render(&mut state, false);
+
+        Transfer {
+            progress: self.clone(),
+            id,
+        }
+    }
+
+    /// Prints a warning to stderr without it being overwritten by the progress
+    /// block
+    pub fn warn(&self, line: impl std::fmt::Display) {
+        let mut state = self.inner.lock();
+        self.inner.clear(&mut state);
+        eprintln!("warning: {line}");
+
+        if self.inner.tty {
+            self.inner.render(&mut state, true);
+        }
+    }
+
+    /// Prints a line to stdout without it being overwritten by the progress
+    /// block
+    pub fn println(&self, line: impl std::fmt::Display) {
+        let mut state = self.inner.lock();
+        self.inner.clear(&mut state);
+        println!("{line}");
+
+        if self.inner.tty {
+            self.inner.render(&mut state, true);
+        }
+    }
+}
+
+impl Inner {
+    #[inline]
+    fn lock(&self) -> std::sync::MutexGuard<'_, State> {
+        // A panic while holding the lock can't leave the state invalid
+        self.state
+            .lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner)
+    }
+
+    /// Erases the progress block from the terminal
+    fn clear(&self, state: &mut State) {
+        if state.drawn > 0 {
+            let mut stderr = std::io::stderr().lock();
+            let _ = write!(stderr, "\x1b[{}A\r\x1b[J", state.drawn);
+            let _ = stderr.flush();
+            state.drawn = 0;
+        }
+    }
+
+    /// Renders the current progress, unless it was rendered too recently
+    fn render(&self, state: &mut State, force: bool) {
+        let now = Instant::now();
+        let interval = if self.tty {
+            TTY_INTERVAL
+        } else {
+            PLAIN_INTERVAL
+        };
+
+        match state.last_render {
+            Some(last) if !force && now.duration_since(last) < interval => return,
+            // The first plain line is only printed once the transfers have
+            // been running for a while, so that quick commands stay quiet
+            None if !self.tty => {
+                state.last_render = Some(now);
+                return;
+            }
+            _ => {}
+        }
+        state.last_render = Some(now);
+        state.shown = true;
+
+        let mut out = String::new();
+        let mut lines = 0;
+
+        if self.tty {
+            for transfer in state.active.iter().take(MAX_DISPLAYED) {
+                let elapsed = now.duration_since(transfer.started);
+                let _ = writeln!(
+                    out,
+                    "{:<40} {}",
+                    truncate(&transfer.name, 40),
+                    stats(transfer.done, transfer.total, elapsed, true)
+                );
+                lines += 1;
+            }
+
+            if state.active.len() > MAX_DISPLAYED {
+                let _ = writeln!(out, "... and {} more", state.active.len() - MAX_DISPLAYED);
+                lines += 1;
+            }
+        } else {
+            for transfer in &state.active {
+                let elapsed = now.duration_since(transfer.started);
+                let _ = writeln!(
+                    out,
+                    "  {}: {}",
+                    transfer.name,
+                    stats(transfer.done, transfer.total, elapsed, false)
+                );
+            }
+        }
+
+        let elapsed = state
+            .started
+            .map_or(Duration::ZERO, |s| now.duration_since(s));
+        let files = format!("{}/{} files", state.finished_files, state.files);
+        let total = (state.total > 0).then_some(state.total);
+        let aggregate = if self.tty {
+            format!("{:<40} {}", files, stats(state.done, total, elapsed, true))
+        } else {
+            format!(
+                "progress: {files}, {}",
+                stats(state.done, total, elapsed, false)
+            )
+        };
+
+        let mut stderr = std::io::stderr().lock();
+
+        if self.tty {
+            let _ = writeln!(out, "{aggregate}");
+            lines += 1;
+
+            // Erase the previous block as part of the same write to avoid flicker
+            let erase = if state.drawn > 0 {
+                format!("\x1b[{}A\r\x1b[J", state.drawn)
+            } else {
+                String::new()
+            };
+            let _ = write!(stderr, "{erase}{out}");
+            state.drawn = lines;
+        } else {
+            let _ = write!(stderr, "{aggregate}\n{out}");
+        }
+
+        let _ = stderr.flush();
+    }
+}
+
+impl Drop for Inner {
+    fn drop(&mut self) {
+        let state = self
+            .state
+            .get_mut()
+            .unwrap_or_else(std::sync::PoisonError::into_inner);
+
+        if state.drawn > 0 {
+            let _ = write!(std::io::stderr(), "\x1b[{}A\r\x1b[J", state.drawn);
+        }
+
+        let Some(started) = state.started.filter(|_| state.shown) else {
+            return;
+        };
+
+        let elapsed = started.elapsed();
+        eprintln!(
+            "Transferred {} in {} file(s) in {:.1}s ({}/s)",
+            bytes_to_human(state.done),
+            state.files,
+            elapsed.as_secs_f64(),
+            bytes_to_human(rate(state.done, elapsed) as u64),
+        );
+    }
+}
+
+/// A handle to a single transfer, eg. of one file or object
+pub struct Transfer {
+    progress: Progress,
+    id: usize,
+}
+
+impl Transfer {
+    /// Adds to the number of bytes that have been transferred
+    pub fn inc(&self, bytes: u64) {
+        let inner = &self.progress.inner;
+        let mut state = inner.lock();
+
+        if let Some(transfer) = state.active.iter_mut().find(|t| t.id == self.id) {
+            transfer.done += bytes;
+        }
+        state.done += bytes;
+
+        inner.render(&mut state, false);
+    }
+}
+
+impl Drop for Transfer {
+    fn drop(&mut self) {
+        let inner = &self.progress.inner;
+        let mut state = inner.lock();
+
+        state.active.retain(|t| t.id != self.id);
+        state.finished_files += 1;
+
+        inner.render(&mut state, inner.tty);
+    }
+}
+
+#[inline]
+fn rate(bytes: u64, elapsed: Duration) -> f64 {
+    let secs = elapsed.as_secs_f64();
+    if secs > 0.0 {
+        bytes as f64 / secs
+    } else {
+        0.0
+    }
+}
+
+/// Formats the amount transferred, the throughput and the ETA, plus a bar if
+/// the output is for a terminal
+fn stats(done: u64, total: Option<u64>, elapsed: Duration, bar: bool) -> String {
+    let rate = rate(done, elapsed);
+    let mut out = String::new();
+
+    match total {
+        Some(total) => {
+            let ratio = if total > 0 {
+                (done as f64 / total as f64).min(1.0)
+            } else {
+                1.0
+            };
+
+            if bar {
+                let filled = (ratio * BAR_WIDTH as f64) as usize;
+                let _ = write!(
+                    out,
+                    "[{}{}] ",
+                    "=".repeat(filled),
+                    " ".repeat(BAR_WIDTH - filled)
+                );
+            }
+
+            let _ = write!(
+                out,
+                "{} / {} ({:.0}%), {}/s",
+                bytes_to_human(done),
+                bytes_to_human(total),
+                ratio * 100.0,
+                bytes_to_human(rate as u64),
+            );
+
+            if rate > 0.0 && done < total {
+                let eta = Duration::from_secs_f64((total - done) as f64 / rate);
+                let _ = write!(out, ", ETA {}", duration_to_human(eta));
+            }
+        }
+        None => {
+            let _ = write!(
+                out,
+                "{}, {}/s",
+                bytes_to_human(done),
+                bytes_to_human(rate as u64)
+            );
+        }
+    }
+
+    out
+}
+
+/// Truncates a name to fit in the specified number of characters, keeping the
+/// end of it as that is usually the most distinct part of a path
+fn truncate(name: &str, max: usize) -> std::borrow::Cow<'_, str> {
+    let len = name.chars().count();
+    if len <= max {
+        return name.into();
+    }
+
+    let tail: String = name.chars().skip(len - (max - 3)).collect();
+    format!("...{tail}").into()
+}
+
+fn duration_to_human(duration: Duration) -> String {
+    let secs = duration.as_secs();
+
+    if secs >= 60 * 60 {
+        format!("{}h{:02}m", secs / 3600, (secs % 3600) / 60)
+    } else if secs >= 60 {
+        format!("{}m{:02}s", secs / 60, secs % 60)
+    } else {
+        format!("{secs}s")
+    }
+}
@@ -0,0 +1,103 @@
+use super::{mb, util};
+use anyhow::Context as _;
+use futures_util::StreamExt as _;
+use tame_gcs::{http, objects, BucketName, ObjectName};
+
+/// Removes one or more buckets
+#[derive(clap::Parser)]
+pub struct Args {
+    /// Removes every object in the buckets, including noncurrent versions,
+    /// before removing the buckets themselves, which must otherwise be empty
+    #[clap(short = 'f', long)]
+    force: bool,
+    /// The maximum number of delete requests that are in flight at once when
+    /// emptying a bucket with `--force`
+    #[clap(short = 'j', long, default_value = "32", value_parser = clap::value_parser!(u32).range(1..))]
+    jobs: u32,
+    /// One or more gs: URLs of the buckets to remove, eg. `gs://bucket`
+    #[clap(required = true)]
+    urls: Vec<String>,
+}
+
+/// Removes every object, and every version of every object, in a bucket
+async fn empty(
+    ctx: &util::RequestContext,
+    bucket: &BucketName<'_>,
+    jobs: u32,
+) -> anyhow::Result<()> {
+    let mut versions = Vec::new();
+    let mut page_token: Option<String> = None;
+
+    loop {
+        let list_req = ctx.obj.list(
+            bucket,
+            Some(objects::ListOptional {
+                page_token: page_token.as_deref(),
+                versions: true,
+                ..Default::default()
+            }),
+        )?;
+
+        let res: objects::ListResponse = util::execute(ctx, list_req).await?;
+        versions.extend(
+            res.objects
+                .into_iter()
+                .filter_map(|md| md.name.map(|name| (name, md.generation))),
+        );
+
+        page_token = res.page_token;
+        if page_token.is_none() {
+            break;
+        }
+    }
+
+    let mut results =
+        futures_util::stream::iter(versions.iter().map(|(name, generation)| async move {
+            let object = ObjectName::try_from(name.as_str())?;
+            let del_req = ctx.obj.delete(
+                &(bucket, &object),
+                Some(objects::DeleteObjectOptional {
+                    generation: *generation,
+                    ..Default::default()
+                }),
+            )?;
+            util::execute::<_, objects::DeleteObjectResponse>(ctx, del_req)
+                .await
+                .with_context(|| format!("failed to remove 'gs://{bucket}/{name}'"))?;
+            anyhow::Ok(())
+        }))
+        .buffer_unordered(jobs as usize);
+
+    while let Some(res) = results.next().await {
+        res?;
+    }
+
+    Ok(())
+}
+
+pub async fn run(ctx: &util::RequestContext, args: Args) -> anyhow::Result<()> {
+    for url in &args.urls {
+        let bucket = mb::parse_bucket_url(url)?;
+
+        if args.force {
+            empty(ctx, &bucket, args.jobs)
+                .await
+                .with_context(|| format!("failed to empty bucket 'gs://{bucket}'"))?;
+        }
+
+        let delete_req = http::Request::builder()
+            .method(http::Method::DELETE)
+            .uri(util::bucket_url(ctx, Some(&bucket))?)
+            .body(std::io::empty())?;
+
+        let response = util::execute_raw(ctx, delete_req).await?;
+        if !response.status().is_success() {
+            return Err(util::api_error(response))
+                .with_context(|| format!("failed to remove bucket 'gs://{bucket}'"));
+        }
+
+        println!("Removed gs://{bucket}");
+    }
+
+    Ok(())
+}
@@ -0,0 +1,577 @@
+//! [Resumable uploads](https://cloud.google.com/storage/docs/resumable-uploads)
+//! which upload an object in chunks, so that a failed request only needs to
+//! resume from the last offset committed by GCS rather than restarting the
+//! entire upload.
+//!
+//! This only builds requests and parses responses, actually sending them is up
+//! to the caller so that this can be used by both async and blocking clients
+
+use anyhow::Context as _;
+use std::{ops::Range, time::Duration};
+use tame_gcs::{
+    http,
+    objects::{self, Metadata},
+    ApiResponse, BucketName, ObjectName,
+};
+
+/// The size of each chunk, GCS requires this to be a multiple of 256KiB for
+/// every chunk but the last
+pub const CHUNK_SIZE: u64 = 16 * 1024 * 1024;
+
+pub type Request = http::Request<std::io::Cursor<Vec<u8>>>;
+
+/// The metadata of an object being uploaded, which extends [`Metadata`] with
+/// the fields that it doesn't support
+#[derive(Default, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct UploadMetadata {
+    #[serde(flatten)]
+    pub metadata: Metadata,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cache_control: Option<String>,
+}
+
+/// Creates the request that initiates a resumable upload session, the response
+/// of which is used to create an [`Upload`]
+pub fn init_request(
+    obj: &objects::Object,
+    bucket: &BucketName<'_>,
+    upload_metadata: &UploadMetadata,
+    optional: Option<objects::InsertObjectOptional<'_>>,
+) -> anyhow::Result<Request> {
+    let metadata = &upload_metadata.metadata;
+    let name = ObjectName::try_from(
+        metadata
+            .name
+            .as_deref()
+            .context("object metadata must contain a name")?,
+    )?;
+
+    let (parts, ()) = obj
+        .resumable_insert_init(&(bucket, &name), metadata.content_type.as_deref())?
+        .into_parts();
+
+    let mut uri = parts.uri.to_string();
+
+    // The init request only takes the object name, but we need to pass along the
+    // same query parameters that a multipart insert would
+    if let serde_json::Value::Object(params) = serde_json::to_value(optional.unwrap_or_default())? {
+        let mut ser = url::form_urlencoded::Serializer::new(String::new());
+
+        for (key, value) in params {
+            match value {
+                serde_json::Value::Null => {}
+                serde_json::Value::String(s) => {
+                    ser.append_pair(&key, &s);
+                }
+                value => {
+                    ser.append_pair(&key, &value.to_string());
+                }
+            }
+        }
+
+        let query = ser.finish();
+        if !query.is_empty() {
+            uri.push('&');
+            uri.push_str(&query);
+        }
+    }
+
+    let body = serde_json::to_vec(upload_metadata)?;
+
+    let mut builder = http::Request::builder().method(parts.method).uri(uri);
+
+    if let Some(headers) = builder.headers_mut() {
+        headers.extend(parts.headers);
+        headers.insert(
+            http::header::CONTENT_TYPE,
+            http::HeaderValue::from_static("application/json; charset=utf-8"),
+        );
+        headers.insert(http::header::CONTENT_LENGTH, body.len().into());
+    }
+
+    Ok(builder.body(std::io::Cursor::new(body))?)
+}
+
+/// Returns true if a chunk that failed with the specified status can be resumed
+#[inline]
+pub fn is_resumable(status: http::StatusCode) -> bool {
+    status.is_server_error()
+        || status == http::StatusCode::REQUEST_TIMEOUT
+        || status == http::StatusCode::TOO_MANY_REQUESTS
+}
+
+/// Converts a failed response into an error
+fn api_error(res: http::Response<bytes::Bytes>) -> anyhow::Error {
+    match res
+        .headers()
+        .get(http::header::CONTENT_TYPE)
+        .and_then(|hv| hv.to_str().ok())
+    {
+        Some(ct) if ct.starts_with("text/plain") => anyhow::anyhow!(
+            "request failed: HTTP status: {} -> {}",
+            res.status(),
+            std::str::from_utf8(res.body()).unwrap_or("text/plain body was not utf8")
+        ),
+        _ => match objects::InsertResponse::try_from_parts(res) {
+            Ok(_) => anyhow::anyhow!("unexpected response to resumable upload"),
+            Err(err) => anyhow::Error::from(err).context("API request failed"),
+        },
+    }
+}
+
+/// What the caller should do after the result of a chunk or status request
+/// has been handled
+#[allow(clippy::large_enum_variant)]
+pub enum Step {
+    /// The upload is complete
+    Complete(Metadata),
+    /// Send the next request immediately
+    Next,
+    /// The request failed, wait for the delay and then resume the upload, the
+    /// next request being a status request to find out where to resume from
+    Resume(Duration),
+}
+
+/// An in progress resumable upload session
+pub struct Upload {
+    session: objects::ResumableSession,
+    /// The total size of the object being uploaded, which is unknown for
+    /// streaming uploads until the end of the stream has been reached
+    total: Option<u64>,
+    /// The number of bytes that GCS has committed so far
+    offset: u64,
+    /// The number of consecutive failed requests since the upload last made
+    /// progress
+    failures: u32,
+    /// Set after a failed request, as the committed offset is then unknown
+    needs_status: bool,
+}
+
+impl Upload {
+    /// Creates the upload from the response to the [`init_request`]
+    pub fn new(init_response: http::Response<bytes::Bytes>, total: u64) -> anyhow::Result<Self> {
+        let mut upload = Self::streaming(init_response)?;
+        upload.total = Some(total);
+        Ok(upload)
+    }
+
+    /// Creates an upload whose total size is not yet known, which must be set
+    /// via [`Self::set_total`] before the final chunk is sent
+    pub fn streaming(init_response: http::Response<bytes::Bytes>) -> anyhow::Result<Self> {
+        if !init_response.status().is_success() {
+            return Err(api_error(init_response).context("failed to initiate resumable upload"));
+        }
+
+        let init = objects::InitResumableInsertResponse::try_from_parts(init_response)
+            .context("failed to initiate resumable upload")?;
+
+        Ok(Self {
+            session: init.resumable_session,
+            total: None,
+            offset: 0,
+            failures: 0,
+            needs_status: false,
+        })
+    }
+
+    /// The number of bytes that GCS has committed so far
+    #[inline]
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    /// Returns true if the next request must be a [`Self::status_request`], to
+    /// find out where to resume the upload from after a failed request
+    #[inline]
+    pub fn needs_status(&self) -> bool {
+        self.needs_status
+    }
+
+    /// The total size of the object, if it is known
+    #[inline]
+    pub fn total(&self) -> Option<u64> {
+        self.total
+    }
+
+    /// Sets the total size of a streaming upload once it is known
+    #[inline]
+    pub fn set_total(&mut self, total: u64) {
+        self.total = Some(total);
+    }
+
+    /// The range of the object that should be sent in the next chunk
+    #[inline]
+    pub fn next_chunk(&self) -> Range<u64> {
+        let end = self.offset + CHUNK_SIZE;
+        self.offset..self.total.map_or(end, |total| std::cmp::min(end, total))
+    }
+
+    /// Creates the request to upload the next chunk, which must contain the
+    /// bytes in the range returned by [`Self::next_chunk`]
+    pub fn chunk_request(&self, chunk: Vec<u8>) -> anyhow::Result<Request> {
+        let len = chunk.len() as u64;
+        anyhow::ensure!(len > 0, "attempted to upload an empty chunk");
+
+        let mut req = objects::Object::resumable_append(
+            self.session.clone(),
+            std::io::Cursor::new(chunk),
+            len,
+        )?;
+
+        req.headers_mut().insert(
+            http::header::CONTENT_RANGE,
+            http::HeaderValue::try_from(format!(
+                "bytes {}-{}/{}",
+                self.offset,
+                self.offset + len - 1,
+                self.total_str()
+            ))?,
+        );
+
+        Ok(req)
+    }
+
+    /// Creates the request used to query how many bytes GCS has committed, which
+    /// is used to resume the upload after a failed chunk. If the total size is
+    /// known and every byte has been committed, this finalizes the upload
+    pub fn status_request(&self) -> anyhow::Result<Request> {
+        let mut req = objects::Object::resumable_append(
+            self.session.clone(),
+            std::io::Cursor::new(Vec::new()),
+            0,
+        )?;
+
+        req.headers_mut().insert(
+            http::header::CONTENT_RANGE,
+            http::HeaderValue::try_from(format!("bytes */{}", self.total_str()))?,
+        );
+
+        Ok(req)
+    }
+
+    /// The total size as it appears in a Content-Range header, where `*`
+    /// denotes that it is not yet known
+    #[inline]
+    fn total_str(&self) -> String {
+        self.total
+            .map_or_else(|| "*".to_owned(), |total| total.to_string())
+    }
+
+    /// Handles the result of sending a chunk or status request. Failures that
+    /// can be resumed are retried according to the policy, the failure count
+    /// only being reset once the upload makes progress, as a status query can
+    /// succeed even while every chunk request fails
+    pub fn handle_result(
+        &mut self,
+        result: anyhow::Result<http::Response<bytes::Bytes>>,
+        policy: &super::retry::Policy,
+    ) -> anyhow::Result<Step> {
+        let (err, headers) = match result {
+            Ok(res) if !is_resumable(res.status()) => {
+                let committed = self.offset;
+
+                if let Some(metadata) = self.handle_response(res)? {
+                    return Ok(Step::Complete(metadata));
+                }
+
+                if self.offset > committed {
+                    self.failures = 0;
+                }
+                self.needs_status = false;
+                return Ok(Step::Next);
+            }
+            Ok(res) => (
+                anyhow::anyhow!("HTTP status: {}", res.status()),
+                Some(res.headers().clone()),
+            ),
+            Err(err) => (err, None),
+        };
+
+        self.failures += 1;
+        self.needs_status = true;
+
+        match policy.retry_delay(self.failures, headers.as_ref()) {
+            Some(delay) => Ok(Step::Resume(delay)),
+            None => Err(err.context("exceeded the maximum number of resume attempts")),
+        }
+    }
+
+    /// Updates the committed offset from the response to a chunk or status
+    /// request, returning the object metadata once the upload is complete
+    fn handle_response(
+        &mut self,
+        res: http::Response<bytes::Bytes>,
+    ) -> anyhow::Result<Option<Metadata>> {
+        let status = res.status();
+
+        if status == http::StatusCode::PERMANENT_REDIRECT {
+            // GCS (ab)uses 308 to signal that the upload is incomplete, the range
+            // header contains the bytes that have been committed, and won't
+            // be present at all if nothing has been committed yet
+            self.offset = match res.headers().get(http::header::RANGE) {
+                Some(range) => {
+                    let end: u64 = range
+                        .to_str()
+                        .ok()
+                        .and_then(|range| range.rsplit('-').next())
+                        .and_then(|end| end.parse().ok())
+                        .with_context(|| format!("invalid range header {range:?}"))?;
+                    end + 1
+                }
+                None => 0,
+            };
+
+            Ok(None)
+        } else if status.is_success() {
+            let res = objects::InsertResponse::try_from_parts(res)
+                .context("failed to parse object metadata")?;
+
+            // The final chunk has been committed along with everything before it
+            if let Some(total) = self.total {
+                self.offset = total;
+            }
+
+            Ok(Some(res.metadata))
+        } else {
+            Err(api_error(res))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gcs::retry::Policy;
+
+    fn upload(total: Option<u64>) -> Upload {
+        let init = http::Response::builder()
+            .header(
+                http::header::LOCATION,
+                "https://example.com/upload?upload_id=1",
+            )
+            .body(bytes::Bytes::new())
+            .unwrap();
+
+        match total {
+            Some(total) => Upload::new(init, total).unwrap(),
+            None => Upload::streaming(init).unwrap(),
+        }
+    }
+
+    fn incomplete(range: Option<&str>) -> http::Response<bytes::Bytes> {
+        let mut builder = http::Response::builder().status(http::StatusCode::PERMANENT_REDIRECT);
+        if let Some(range) = range {
+            builder = builder.header(http::header::RANGE, range);
+        }
+        builder.body(bytes::Bytes::new()).unwrap()
+    }
+
+    fn status(status: u16) -> anyhow::Result<http::Response<bytes::Bytes>> {
+        Ok(http::Response::builder()
+            .status(status)
+            .body(bytes::Bytes::new())
+            .unwrap())
+    }
+
+    #[test]
+    fn parses_committed_range() {
+        let mut up = upload(Some(CHUNK_SIZE * 2));
+        assert!(up
+            .handle_response(incomplete(Some("bytes=0-262143")))
+            .unwrap()
+            .is_none());
+        assert_eq!(up.offset(), 262144);
+        assert_eq!(up.next_chunk(), 262144..262144 + CHUNK_SIZE);
+
+        // Nothing has been committed yet
+        assert!(up.handle_response(incomplete(None)).unwrap().is_none());
+        assert_eq!(up.offset(), 0);
+
+        assert!(up.handle_response(incomplete(Some("bytes=0-"))).is_err());
+        assert!(up.handle_response(incomplete(Some("nope"))).is_err());
+    }
+
+    #[test]
+    fn completes() {
+        let mut up = upload(Some(100));
+        assert_eq!(up.next_chunk(), 0..100);
+
+        let res = http::Response::builder()
+            .body(bytes::Bytes::from_static(
+                br#"{"name":"obj","bucket":"bkt"}"#,
+            ))
+            .unwrap();
+
+        match up.handle_result(Ok(res), &Policy::new(3)).unwrap() {
+            Step::Complete(md) => assert_eq!(md.name.as_deref(), Some("obj")),
+            _ => panic!("expected the upload to be complete"),
+        }
+        assert_eq!(up.offset(), 100);
+    }
+
+    #[test]
+    fn streaming_chunks() {
+        let mut up = upload(None);
+        assert_eq!(up.next_chunk(), 0..CHUNK_SIZE);
+
+        up.set_total(10);
+        assert_eq!(up.next_chunk(), 0..10);
+    }
+
+    #[test]
+    fn resumes_from_committed_offset() {
+        let policy = Policy::new(2);
+        let mut up = upload(Some(CHUNK_SIZE * 2));
+
+        assert!(matches!(
+            up.handle_result(status(503), &policy).unwrap(),
+            Step::Resume(_)
+        ));
+        assert!(up.needs_status());
+
+        // The status query succeeding doesn't count as progress
+        assert!(matches!(
+            up.handle_result(Ok(incomplete(None)), &policy).unwrap(),
+            Step::Next
+        ));
+        assert!(!up.needs_status());
+        assert!(up.handle_result(status(503), &policy).is_err());
+
+        let mut up = upload(Some(CHUNK_SIZE * 2));
+        assert!(matches!(
+            up.handle_result(status(500), &policy).unwrap(),
+            Step::Resume(_)
+        ));
+
+        // But committing more of the object does
+        assert!(matches!(
+            up.handle_result(Ok(incomplete(Some("bytes=0-1023"))), &policy)
+                .unwrap(),
+            Step::Next
+        ));
+        assert_eq!(up.offset(), 1024);
+        assert!(matches!(
+            up.handle_result(status(429), &policy).unwrap(),
+            Step::Resume(_)
+        ));
+    }
+
+    fn content_range(req: &Request) -> &str {
+        req.headers()[http::header::CONTENT_RANGE].to_str().unwrap()
+    }
+
+    #[test]
+    fn resumes_across_failures() {
+        let policy = Policy::new(2);
+        let total = CHUNK_SIZE * 2 + 100;
+        let mut up = upload(Some(total));
+
+        let chunk = up.next_chunk();
+        assert_eq!(chunk, 0..CHUNK_SIZE);
+        let req = up.chunk_request(vec![0; CHUNK_SIZE as usize]).unwrap();
+        assert_eq!(
+            content_range(&req),
+            format!("bytes 0-{}/{total}", CHUNK_SIZE - 1)
+        );
+
+        assert!(matches!(
+            up.handle_result(
+                Ok(incomplete(Some(&format!("bytes=0-{}", CHUNK_SIZE - 1)))),
+                &policy
+            )
+            .unwrap(),
+            Step::Next
+        ));
+        assert_eq!(up.next_chunk(), CHUNK_SIZE..CHUNK_SIZE * 2);
+
+        // The connection failed, so how much of the chunk was committed is unknown
+        assert!(matches!(
+            up.handle_result(Err(anyhow::anyhow!("connection reset")), &policy)
+                .unwrap(),
+            Step::Resume(_)
+        ));
+        assert!(up.needs_status());
+        assert_eq!(
+            content_range(&up.status_request().unwrap()),
+            format!("bytes */{total}")
+        );
+
+        // GCS only committed part of the chunk, so the next one starts there
+        let committed = CHUNK_SIZE + 256 * 1024;
+        assert!(matches!(
+            up.handle_result(
+                Ok(incomplete(Some(&format!("bytes=0-{}", committed - 1)))),
+                &policy
+            )
+            .unwrap(),
+            Step::Next
+        ));
+        assert!(!up.needs_status());
+        assert_eq!(up.next_chunk(), committed..total);
+        let req = up
+            .chunk_request(vec![0; (total - committed) as usize])
+            .unwrap();
+        assert_eq!(
+            content_range(&req),
+            format!("bytes {committed}-{}/{total}", total - 1)
+        );
+
+        // The partial commit was progress, so the policy allows another resume,
+        // which waits as long as GCS asked
+        let res = http::Response::builder()
+            .status(503)
+            .header(http::header::RETRY_AFTER, "2")
+            .body(bytes::Bytes::new())
+            .unwrap();
+        match up.handle_result(Ok(res), &policy).unwrap() {
+            Step::Resume(delay) => assert_eq!(delay, Duration::from_secs(2)),
+            _ => panic!("expected the upload to be resumed"),
+        }
+
+        // Everything but the final chunk has been committed
+        assert!(matches!(
+            up.handle_result(
+                Ok(incomplete(Some(&format!("bytes=0-{}", CHUNK_SIZE * 2 - 1)))),
+                &policy
+            )
+            .unwrap(),
+            Step::Next
+        ));
+        assert_eq!(up.next_chunk(), CHUNK_SIZE * 2..total);
+        assert_eq!(
+            content_range(&up.chunk_request(vec![0; 100]).unwrap()),
+            format!("bytes {}-{}/{total}", CHUNK_SIZE * 2, total - 1)
+        );
+
+        let res = http::Response::builder()
+            .body(bytes::Bytes::from_static(
+                br#"{"name":"obj","bucket":"bkt"}"#,
+            ))
+            .unwrap();
+        assert!(matches!(
+            up.handle_result(Ok(res), &policy).unwrap(),
+            Step::Complete(_)
+        ));
+        assert_eq!(up.offset(), total);
+    }
+
+    #[test]
+    fn streaming_status_has_unknown_total() {
+        let mut up = upload(None);
+        assert_eq!(content_range(&up.status_request().unwrap()), "bytes */*");
+        assert_eq!(
+            content_range(&up.chunk_request(vec![0; 10]).unwrap()),
+            "bytes 0-9/*"
+        );
+
+        up.set_total(10);
+        assert_eq!(content_range(&up.status_request().unwrap()), "bytes */10");
+    }
+
+    #[test]
+    fn fails_on_unresumable_status() {
+        let mut up = upload(Some(100));
+        assert!(up.handle_result(status(403), &Policy::new(3)).is_err());
+    }
+}
@@ -0,0 +1,122 @@
+use super::{buckets, mb, util};
+
+/// Manages the retention policy of a bucket, which prevents objects from being
+/// removed or replaced until they reach the retention period
+#[derive(clap::Subcommand)]
+pub enum Args {
+    /// Prints the retention policy of a bucket
+    Get {
+        /// The gs: URL of the bucket
+        url: String,
+    },
+    /// Sets the retention period of a bucket. The period can't be reduced if
+    /// the policy has been locked
+    Set {
+        /// The retention period, eg. `30d` or `12h`
+        #[clap(value_parser = crate::parse_duration)]
+        period: std::time::Duration,
+        /// The gs: URL of the bucket
+        url: String,
+    },
+    /// Removes the retention policy of a bucket, which isn't possible if the
+    /// policy has been locked
+    Clear {
+        /// The gs: URL of the bucket
+        url: String,
+    },
+}
+
+/// The maximum retention period GCS allows, 100 years
+const MAX_PERIOD: u64 = 3_155_760_000;
+
+fn validate(period: std::time::Duration) -> anyhow::Result<()> {
+    anyhow::ensure!(
+        period.as_secs() > 0,
+        "the retention period must be non-zero"
+    );
+    anyhow::ensure!(
+        period.as_secs() <= MAX_PERIOD,
+        "the retention period can be at most {MAX_PERIOD} seconds (100 years)"
+    );
+
+    Ok(())
+}
+
+pub async fn run(ctx: &util::RequestContext, args: Args) -> anyhow::Result<()> {
+    match args {
+        Args::Get { url } => {
+            let bucket = mb::parse_bucket_url(&url)?;
+            let resource = buckets::get(ctx, &bucket, "retentionPolicy").await?;
+
+            let Some(policy) = resource.get("retentionPolicy") else {
+                println!("gs://{bucket} has no retention policy");
+                return Ok(());
+            };
+
+            let field = |key: &str| {
+                policy.get(key).map(|v| match v {
+                    serde_json::Value::String(s) => s.clone(),
+                    v => v.to_string(),
+                })
+            };
+
+            println!("gs://{bucket}:");
+            if let Some(period) = field("retentionPeriod") {
+                println!("    {:<24}{period} seconds", "Retention period:");
+            }
+            if let Some(effective) = field("effectiveTime") {
+                println!("    {:<24}{effective}", "Effective time:");
+            }
+            println!(
+                "    {:<24}{}",
+                "Locked:",
+                field("isLocked").as_deref().unwrap_or("false")
+            );
+        }
+        Args::Set { period, url } => {
+            let bucket = mb::parse_bucket_url(&url)?;
+            validate(period)?;
+
+            buckets::patch(
+                ctx,
+                &bucket,
+                &serde_json::json!({
+                    "retentionPolicy": { "retentionPeriod": period.as_secs().to_string() },
+                }),
+            )
+            .await?;
+            println!(
+                "Set retention period of gs://{bucket} to {} seconds",
+                period.as_secs()
+            );
+        }
+        Args::Clear { url } => {
+            let bucket = mb::parse_bucket_url(&url)?;
+            buckets::patch(
+                ctx,
+                &bucket,
+                &serde_json::json!({ "retentionPolicy": null }),
+            )
+            .await?;
+            println!("Removed retention policy of gs://{bucket}");
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn validates_period() {
+        let period = |s| validate(crate::parse_duration(s).unwrap());
+
+        assert!(period("30d").is_ok());
+        assert!(period("1").is_ok());
+        assert!(period("36525d").is_ok());
+        assert!(period("0d").is_err());
+        assert!(period("36526d").is_err());
+    }
+}
@@ -0,0 +1,368 @@
+//! Retries of failed requests, following GCS's [retry strategy](https://cloud.google.com/storage/docs/retry-strategy)
+//!
+//! Like [`super::resumable`], this doesn't send requests itself so that it can
+//! be used by both async and blocking clients
+
+use std::time::Duration;
+use tame_gcs::http;
+
+/// The longest delay a `Retry-After` header is honored for, so that a bogus or
+/// far off date doesn't stall a command indefinitely
+const MAX_RETRY_AFTER: Duration = Duration::from_secs(60);
+
+/// Determines how many times, and how long to wait before, a failed request
+/// is retried
+#[derive(Clone, Copy)]
+pub struct Policy {
+    /// The maximum number of times a request is sent, including the first
+    pub max_attempts: u32,
+    /// The delay before the first retry, which doubles with each attempt
+    pub initial_backoff: Duration,
+    /// The maximum delay between attempts
+    pub max_backoff: Duration,
+}
+
+impl Policy {
+    pub fn new(max_attempts: u32) -> Self {
+        Self {
+            max_attempts,
+            initial_backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(32),
+        }
+    }
+
+    /// The time to wait after the specified, 1-based, failed attempt. This is
+    /// the delay from the `Retry-After` header if the response had one, up to
+    /// a minute, otherwise an exponential backoff with full jitter so that concurrent
+    /// requests that failed at the same time don't all retry at the same time
+    pub fn delay(&self, attempt: u32, headers: Option<&http::HeaderMap>) -> Duration {
+        if let Some(retry_after) = headers.and_then(retry_after) {
+            return retry_after.min(MAX_RETRY_AFTER);
+        }
+
+        let backoff = self
+            .initial_backoff
+            .saturating_mul(1 << attempt.saturating_sub(1).min(16))
+            .min(self.max_backoff);
+
+        backoff.mul_f64(fastrand::f64())
+    }
+
+    /// The time to wait after the specified, 1-based, failed attempt, or `None`
+    /// if every attempt has been used up
+    #[inline]
+    pub fn retry_delay(&self, attempt: u32, headers: Option<&http::HeaderMap>) -> Option<Duration> {
+        (attempt < self.max_attempts).then(|| self.delay(attempt, headers))
+    }
+
+    /// Determines whether a request should be sent again after the specified,
+    /// 1-based, attempt, returning how long to wait before doing so
+    pub fn next_attempt(
+        &self,
+        attempt: u32,
+        idempotent: bool,
+        result: Attempt<'_>,
+    ) -> Option<Duration> {
+        if !idempotent {
+            return None;
+        }
+
+        match result {
+            Attempt::Response(status, headers) if is_retryable_status(status) => {
+                self.retry_delay(attempt, Some(headers))
+            }
+            Attempt::Error(err) if is_retryable_error(err) => self.retry_delay(attempt, None),
+            _ => None,
+        }
+    }
+}
+
+/// The result of an attempt to send a request, regardless of whether it was
+/// sent by an async or a blocking client
+pub enum Attempt<'a> {
+    /// A response with the specified status and headers was received
+    Response(http::StatusCode, &'a http::HeaderMap),
+    /// The request failed without a response
+    Error(&'a reqwest::Error),
+}
+
+impl Default for Policy {
+    fn default() -> Self {
+        Self::new(5)
+    }
+}
+
+/// Parses the `Retry-After` header, which is either a number of seconds or an
+/// HTTP date
+pub fn retry_after(headers: &http::HeaderMap) -> Option<Duration> {
+    let value = headers
+        .get(http::header::RETRY_AFTER)?
+        .to_str()
+        .ok()?
+        .trim();
+
+    if let Ok(secs) = value.parse() {
+        return Some(Duration::from_secs(secs));
+    }
+
+    let when = httpdate::parse_http_date(value).ok()?;
+    Some(
+        when.duration_since(std::time::SystemTime::now())
+            .unwrap_or_default(),
+    )
+}
+
+/// Returns true if a response with the specified status can be retried
+#[inline]
+pub fn is_retryable_status(status: http::StatusCode) -> bool {
+    matches!(status.as_u16(), 408 | 429 | 500 | 502 | 503 | 504)
+}
+
+/// Returns true if a request that failed with the specified error, ie. without
+/// a response, can be retried
+#[inline]
+pub fn is_retryable_error(err: &reqwest::Error) -> bool {
+    err.is_timeout() || err.is_connect() || err.is_request() || err.is_body()
+}
+
+/// Overrides whether a request is idempotent when inserted into its extensions,
+/// for requests whose idempotency depends on their body rather than their
+/// method and query, see [`is_idempotent`]
+#[derive(Clone, Copy)]
+pub struct Idempotent(pub bool);
+
+/// Returns true if the request can be retried, either because it was marked as
+/// [`Idempotent`], or as determined by [`is_idempotent`]
+pub fn is_request_idempotent<B>(req: &http::Request<B>) -> bool {
+    req.extensions().get::<Idempotent>().map_or_else(
+        || is_idempotent(req.method(), req.uri().query()),
+        |idempotent| idempotent.0,
+    )
+}
+
+/// Returns true if sending the request more than once has the same effect as
+/// sending it once, and can thus be retried. Requests that modify objects are
+/// only idempotent if they are guarded by a precondition, otherwise a retry
+/// could eg. overwrite a change made by someone else in the meantime
+///
+/// Which precondition guards a request depends on its method, uploads, copies
+/// and composes (`POST`) are only guarded by `ifGenerationMatch`, while
+/// metadata changes (`PATCH` and `PUT`) are only guarded by `ifMetagenerationMatch`
+pub fn is_idempotent(method: &http::Method, query: Option<&str>) -> bool {
+    let params: Vec<_> =
+        url::form_urlencoded::parse(query.unwrap_or_default().as_bytes()).collect();
+    let has_param = |name: &str| params.iter().any(|(key, _)| key == name);
+
+    // Requests in a resumable upload session are retried by resuming the
+    // upload from the offset GCS has committed, see `resumable`
+    if has_param("upload_id") {
+        return false;
+    }
+
+    match *method {
+        http::Method::GET | http::Method::HEAD | http::Method::OPTIONS => true,
+        http::Method::DELETE => has_param("generation") || has_param("ifGenerationMatch"),
+        http::Method::PATCH | http::Method::PUT => has_param("ifMetagenerationMatch"),
+        http::Method::POST => {
+            has_param("ifGenerationMatch")
+                // Initiating a resumable upload doesn't modify anything
+                || params
+                    .iter()
+                    .any(|(key, value)| key == "uploadType" && value == "resumable")
+        }
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headers(retry_after: &str) -> http::HeaderMap {
+        let mut headers = http::HeaderMap::new();
+        headers.insert(
+            http::header::RETRY_AFTER,
+            http::HeaderValue::from_str(retry_after).unwrap(),
+        );
+        headers
+    }
+
+    #[test]
+    fn parses_retry_after() {
+        assert_eq!(retry_after(&headers("30")), Some(Duration::from_secs(30)));
+        assert_eq!(retry_after(&headers(" 0 ")), Some(Duration::ZERO));
+        assert_eq!(
+            retry_after(&headers("Wed, 21 Oct 2015 07:28:00 GMT")),
+            Some(Duration::ZERO)
+        );
+        assert_eq!(retry_after(&headers("soon")), None);
+        assert_eq!(retry_after(&http::HeaderMap::new()), None);
+
+        let when = std::time::SystemTime::now() + Duration::from_secs(120);
+        let delay = retry_after(&headers(&httpdate::fmt_http_date(when))).unwrap();
+        assert!(delay > Duration::from_secs(100) && delay <= Duration::from_secs(120));
+    }
+
+    #[test]
+    fn caps_retry_after() {
+        let policy = Policy::default();
+        assert_eq!(policy.delay(1, Some(&headers("3600"))), MAX_RETRY_AFTER);
+        assert_eq!(policy.delay(1, Some(&headers("2"))), Duration::from_secs(2));
+        assert!(policy.delay(10, None) <= policy.max_backoff);
+    }
+
+    #[test]
+    fn retries_idempotent_failures() {
+        let policy = Policy::new(3);
+        let empty = http::HeaderMap::new();
+        let unavailable = Attempt::Response(http::StatusCode::SERVICE_UNAVAILABLE, &empty);
+
+        assert!(policy.next_attempt(1, true, unavailable).is_some());
+        assert!(policy
+            .next_attempt(
+                1,
+                false,
+                Attempt::Response(http::StatusCode::SERVICE_UNAVAILABLE, &empty)
+            )
+            .is_none());
+        assert!(policy
+            .next_attempt(
+                3,
+                true,
+                Attempt::Response(http::StatusCode::SERVICE_UNAVAILABLE, &empty)
+            )
+            .is_none());
+        assert!(policy
+            .next_attempt(
+                1,
+                true,
+                Attempt::Response(http::StatusCode::NOT_FOUND, &empty)
+            )
+            .is_none());
+        assert_eq!(
+            policy.next_attempt(
+                2,
+                true,
+                Attempt::Response(http::StatusCode::TOO_MANY_REQUESTS, &headers("7"))
+            ),
+            Some(Duration::from_secs(7))
+        );
+    }
+
+    #[test]
+    fn idempotency() {
+        use http::Method;
+
+        assert!(is_idempotent(&Method::GET, None));
+        assert!(is_idempotent(&Method::GET, Some("alt=media")));
+        assert!(is_idempotent(&Method::HEAD, None));
+
+        assert!(!is_idempotent(&Method::DELETE, None));
+        assert!(is_idempotent(&Method::DELETE, Some("generation=1")));
+
+        assert!(!is_idempotent(&Method::POST, Some("uploadType=multipart")));
+        assert!(is_idempotent(
+            &Method::POST,
+            Some("uploadType=multipart&ifGenerationMatch=0")
+        ));
+        assert!(is_idempotent(
+            &Method::PATCH,
+            Some("ifMetagenerationMatch=3")
+        ));
+        assert!(!is_idempotent(&Method::PATCH, None));
+        assert!(is_idempotent(&Method::POST, Some("uploadType=resumable")));
+
+        assert!(!is_idempotent(&Method::PUT, Some("upload_id=abc")));
+        assert!(!is_idempotent(
+            &Method::POST,
+            Some("upload_id=abc&ifGenerationMatch=0")
+        ));
+    }
+
+    #[test]
+    fn put_requires_metageneration() {
+        use http::Method;
+
+        assert!(!is_idempotent(&Method::PUT, None));
+        assert!(!is_idempotent(&Method::PUT, Some("ifGenerationMatch=1")));
+        assert!(is_idempotent(&Method::PUT, Some("ifMetagenerationMatch=2")));
+    }
+
+    #[test]
+    fn post_requires_generation() {
+        use http::Method;
+
+        assert!(!is_idempotent(
+            &Method::POST,
+            Some("uploadType=multipart&ifMetagenerationMatch=1")
+        ));
+        assert!(!is_idempotent(
+            &Method::POST,
+            Some("ifSourceGenerationMatch=1&ifMetagenerationMatch=1")
+        ));
+        assert!(is_idempotent(
+            &Method::POST,
+            Some("ifSourceGenerationMatch=1&ifGenerationMatch=0")
+        ));
+    }
+
+    #[test]
+    fn classifies_requests() {
+        use tame_gcs::objects::{InsertObjectOptional, Metadata, Object};
+
+        let bucket = tame_gcs::BucketName::non_validated("bucket");
+        let metadata = Metadata {
+            name: Some("object".to_owned()),
+            ..Default::default()
+        };
+        let insert = |optional| {
+            Object::default()
+                .insert_multipart(&bucket, std::io::empty(), 0, &metadata, optional)
+                .unwrap()
+        };
+
+        let guarded = |conditionals| {
+            Some(InsertObjectOptional {
+                conditionals,
+                ..Default::default()
+            })
+        };
+
+        assert!(!is_request_idempotent(&insert(None)));
+        assert!(is_request_idempotent(&insert(guarded(
+            tame_gcs::common::Conditionals {
+                if_generation_match: Some(0),
+                ..Default::default()
+            }
+        ))));
+        assert!(!is_request_idempotent(&insert(guarded(
+            tame_gcs::common::Conditionals {
+                if_metageneration_match: Some(1),
+                ..Default::default()
+            }
+        ))));
+
+        let object = tame_gcs::ObjectName::non_validated("object");
+        let download = Object::default()
+            .download(&(&bucket, &object), None)
+            .unwrap();
+        assert!(is_request_idempotent(&download));
+
+        // An IAM policy update is a PUT whose idempotency depends on whether
+        // the policy in its body has an etag
+        let set_policy = |etag| {
+            let mut req = http::Request::builder()
+                .method(http::Method::PUT)
+                .uri("https://storage.googleapis.com/storage/v1/b/bucket/iam")
+                .body(())
+                .unwrap();
+            if let Some(etag) = etag {
+                req.extensions_mut().insert(Idempotent(etag));
+            }
+            req
+        };
+        assert!(!is_request_idempotent(&set_policy(None)));
+        assert!(!is_request_idempotent(&set_policy(Some(false))));
+        assert!(is_request_idempotent(&set_policy(Some(true))));
+    }
+}
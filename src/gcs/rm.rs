@@ -0,0 +1,258 @@
+use super::{util, wildcard};
+use anyhow::Context as _;
+use futures_util::StreamExt as _;
+use nu_ansi_term::Color;
+use tame_gcs::{objects, BucketName, ObjectName};
+
+/// Removes objects
+#[derive(clap::Parser)]
+pub struct Args {
+    /// Removes every object beneath each prefix, including "directory"
+    /// placeholder objects
+    #[clap(short = 'r', long)]
+    recursive: bool,
+    /// Prints the objects that would be removed, without actually removing them
+    #[clap(long)]
+    dry_run: bool,
+    /// The maximum number of delete requests that are in flight at once
+    #[clap(short = 'j', long, default_value = "32", value_parser = clap::value_parser!(u32).range(1..))]
+    jobs: u32,
+    #[clap(flatten)]
+    preconditions: util::Preconditions,
+    /// One or more gs: URLs of the objects to remove. Supports `*`, `**` and `?`
+    /// wildcards, and prefixes when `-r` is specified. A specific generation of
+    /// an object can be removed via `gs://bucket/object#<generation>`
+    #[clap(required = true)]
+    urls: Vec<String>,
+}
+
+/// An object to remove
+struct Target {
+    name: String,
+    /// The generation specified by the url, which is removed rather than the
+    /// live object
+    generation: Option<i64>,
+    /// The generation of the live object when it was listed
+    listed: Option<i64>,
+}
+
+impl Target {
+    fn listed(md: objects::Metadata) -> Option<Self> {
+        Some(Self {
+            name: md.name?,
+            generation: None,
+            listed: md.generation,
+        })
+    }
+
+    /// The options of the delete request. A listed object is only removed if
+    /// it is still the generation that was listed, so that an object replaced
+    /// since then isn't removed, and so that the delete can be retried
+    fn delete_optional(
+        &self,
+        preconditions: util::Preconditions,
+    ) -> objects::DeleteObjectOptional<'static> {
+        objects::DeleteObjectOptional {
+            generation: self.generation,
+            conditionals: util::Preconditions {
+                if_generation_match: preconditions.if_generation_match.or(self.listed),
+                ..preconditions
+            }
+            .conditionals(),
+            ..Default::default()
+        }
+    }
+}
+
+/// Gathers every object targeted by the url
+async fn gather(
+    ctx: &util::RequestContext,
+    url: &str,
+    recursive: bool,
+) -> anyhow::Result<(BucketName<'static>, Vec<Target>)> {
+    if wildcard::has_wildcard(url) {
+        let (bucket, pattern) = wildcard::parse_gs_pattern(url)?;
+        let names = wildcard::expand_gcs(ctx, &bucket, &pattern)
+            .await?
+            .into_iter()
+            .filter_map(Target::listed)
+            .collect();
+
+        return Ok((bucket, names));
+    }
+
+    let parsed = url::Url::parse(url)?;
+    let gs_url = util::gs_url_to_object_id(&parsed)?;
+    let bucket = BucketName::try_from(gs_url.bucket().to_string())?;
+
+    if !recursive {
+        let object = gs_url
+            .object()
+            .with_context(|| format!("'{url}' is a bucket, use -r to remove all of its objects"))?;
+        return Ok((
+            bucket,
+            vec![Target {
+                name: object.to_string(),
+                generation: gs_url.generation(),
+                listed: None,
+            }],
+        ));
+    }
+
+    anyhow::ensure!(
+        gs_url.generation().is_none(),
+        "'{url}' specifies a generation, which can't be used with -r"
+    );
+
+    // Treat the object as a "directory" so that eg. gs://bucket/dir doesn't
+    // also remove gs://bucket/dir-other/
+    let prefix = gs_url.object().map(|obn| {
+        let mut prefix = obn.to_string();
+        if !prefix.ends_with('/') {
+            prefix.push('/');
+        }
+        prefix
+    });
+
+    let mut names: Vec<_> = util::list_objects(ctx, &bucket, prefix.as_deref())
+        .await?
+        .into_iter()
+        .filter_map(Target::listed)
+        .collect();
+
+    // A placeholder object for the "directory" itself isn't part of the listing
+    // if it was created without a trailing slash
+    if let Some(obn) = gs_url.object() {
+        if !AsRef::<str>::as_ref(obn).ends_with('/') {
+            let get_req = ctx.obj.get(&(&bucket, obn), None)?;
+            let response = util::execute_raw(ctx, get_req).await?;
+
+            // Only a missing object is expected, any other failure would
+            // otherwise silently leave the object in place
+            match response.status() {
+                http::StatusCode::NOT_FOUND => {}
+                status if status.is_success() => {
+                    use tame_gcs::ApiResponse;
+                    let md = objects::GetObjectResponse::try_from_parts(response)?.metadata;
+                    names.extend(Target::listed(md));
+                }
+                _ => return Err(util::api_error(response)),
+            }
+        }
+    }
+
+    anyhow::ensure!(!names.is_empty(), "no objects matched '{url}'");
+
+    Ok((bucket, names))
+}
+
+/// Formats the url of an object, including its generation if one was specified
+fn display_url(bucket: &BucketName<'_>, name: &str, generation: Option<i64>) -> String {
+    match generation {
+        Some(gen) => format!("gs://{bucket}/{name}#{gen}"),
+        None => format!("gs://{bucket}/{name}"),
+    }
+}
+
+pub async fn run(ctx: &util::RequestContext, args: Args) -> anyhow::Result<()> {
+    let mut gathered = Vec::with_capacity(args.urls.len());
+    for url in &args.urls {
+        gathered.push(gather(ctx, url, args.recursive).await?);
+    }
+
+    let targets: Vec<_> = gathered
+        .iter()
+        .flat_map(|(bucket, targets)| targets.iter().map(move |target| (bucket, target)))
+        .collect();
+
+    if args.dry_run {
+        for &(bucket, target) in &targets {
+            println!(
+                "Would remove {}",
+                display_url(bucket, &target.name, target.generation)
+            );
+        }
+
+        return Ok(());
+    }
+
+    let total = targets.len();
+    let preconditions = args.preconditions;
+
+    let mut results =
+        futures_util::stream::iter(targets.iter().map(|&(bucket, target)| async move {
+            let res = async {
+                let object = ObjectName::try_from(target.name.as_str())?;
+                let del_req = ctx.obj.delete(
+                    &(bucket, &object),
+                    Some(target.delete_optional(preconditions)),
+                )?;
+                util::execute::<_, objects::DeleteObjectResponse>(ctx, del_req).await?;
+                anyhow::Ok(())
+            }
+            .await;
+
+            (display_url(bucket, &target.name, target.generation), res)
+        }))
+        .buffer_unordered(args.jobs as usize);
+
+    let mut failures = 0;
+    while let Some((url, res)) = results.next().await {
+        match res {
+            Ok(()) => {
+                println!("{} {url}", Color::Green.paint("Removed"));
+            }
+            Err(err) => {
+                println!(
+                    "{} {url}\n  {}",
+                    Color::Red.paint("ERR"),
+                    Color::Red.paint(format!("{err:#}"))
+                );
+                failures += 1;
+            }
+        }
+    }
+
+    anyhow::ensure!(
+        failures == 0,
+        "failed to remove {failures} of {total} objects"
+    );
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn guards_listed_objects() {
+        let listed = Target::listed(objects::Metadata {
+            name: Some("obj".to_owned()),
+            generation: Some(7),
+            ..Default::default()
+        })
+        .unwrap();
+
+        let opt = listed.delete_optional(util::Preconditions::default());
+        assert_eq!(opt.generation, None);
+        assert_eq!(opt.conditionals.if_generation_match, Some(7));
+
+        // The user's own precondition takes precedence
+        let opt = listed.delete_optional(util::Preconditions {
+            if_generation_match: Some(3),
+            if_metageneration_match: Some(2),
+        });
+        assert_eq!(opt.conditionals.if_generation_match, Some(3));
+        assert_eq!(opt.conditionals.if_metageneration_match, Some(2));
+
+        let explicit = Target {
+            name: "obj".to_owned(),
+            generation: Some(5),
+            listed: None,
+        };
+        let opt = explicit.delete_optional(util::Preconditions::default());
+        assert_eq!(opt.generation, Some(5));
+        assert_eq!(opt.conditionals.if_generation_match, None);
+    }
+}
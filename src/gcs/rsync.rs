@@ -0,0 +1,463 @@
+use super::{cp, progress::Progress, util};
+use anyhow::Context as _;
+use nu_ansi_term::Color;
+use std::{
+    collections::BTreeMap,
+    fs,
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicUsize, Ordering},
+    time::SystemTime,
+};
+use tame_gcs::{objects::Metadata, ObjectName};
+
+/// Synchronizes the contents of a destination "directory" with a source
+/// "directory", copying only the files that differ
+#[derive(clap::Parser)]
+pub struct Args {
+    #[clap(flatten)]
+    opts: cp::Options,
+    /// Deletes files or objects in the destination that are not present in the
+    /// source
+    #[clap(short = 'd', long)]
+    delete: bool,
+    /// Compares the crc32c checksums of files whose sizes match, rather than
+    /// their modification times
+    #[clap(short = 'c', long)]
+    checksum: bool,
+    /// The maximum number of files or objects that are transferred or removed
+    /// at once
+    #[clap(short = 'j', long, default_value = "4", value_parser = clap::value_parser!(u32).range(1..))]
+    jobs: u32,
+    /// A gs: URL or local directory to synchronize from
+    src_url: String,
+    /// A gs: URL or local directory to synchronize to
+    dest_url: String,
+}
+
+/// A file in the local directory tree
+struct LocalFile {
+    path: PathBuf,
+    size: u64,
+    modified: SystemTime,
+}
+
+/// Lists every file beneath the directory, keyed by its `/` separated path
+/// relative to the directory
+fn list_local(dir: &Path, progress: &Progress) -> anyhow::Result<BTreeMap<String, LocalFile>> {
+    let mut files = BTreeMap::new();
+
+    // The directory will be created if it is the destination
+    if !dir.exists() {
+        return Ok(files);
+    }
+
+    anyhow::ensure!(dir.is_dir(), "'{}' is not a directory", dir.display());
+
+    for entry in cp::walk_files(walkdir::WalkDir::new(dir).min_depth(1), progress)? {
+        let rel = entry
+            .path()
+            .strip_prefix(dir)?
+            .components()
+            .map(|comp| comp.as_os_str().to_str())
+            .collect::<Option<Vec<_>>>()
+            .with_context(|| format!("path '{}' is not utf-8", entry.path().display()))?
+            .join("/");
+
+        let md = entry
+            .metadata()
+            .with_context(|| format!("failed to read metadata for '{}'", entry.path().display()))?;
+
+        files.insert(
+            rel,
+            LocalFile {
+                size: md.len(),
+                modified: md.modified()?,
+                path: entry.into_path(),
+            },
+        );
+    }
+
+    Ok(files)
+}
+
+/// Lists every object beneath the prefix, keyed by its name relative to the
+/// prefix
+async fn list_remote(
+    ctx: &util::RequestContext,
+    url: &util::GsUrl,
+) -> anyhow::Result<BTreeMap<String, Metadata>> {
+    // Treat the prefix as a "directory" so that eg. gs://bucket/dir doesn't
+    // also match gs://bucket/dir-other/
+    let prefix = url.object().map(|obn| {
+        let mut prefix = obn.to_string();
+        if !prefix.ends_with('/') {
+            prefix.push('/');
+        }
+        prefix
+    });
+
+    let mut objects = BTreeMap::new();
+    for md in util::list_objects(ctx, url.bucket(), prefix.as_deref()).await? {
+        let name = md
+            .name
+            .as_deref()
+            .context("object metadata did not contain a name")?;
+        let rel = &name[prefix.as_ref().map_or(0, |p| p.len())..];
+
+        // Skip "directory" placeholder objects
+        if rel.is_empty() || rel.ends_with('/') {
+            continue;
+        }
+
+        objects.insert(rel.to_owned(), md);
+    }
+
+    Ok(objects)
+}
+
+/// Lists both sides concurrently
+async fn list_both(
+    ctx: &util::RequestContext,
+    dir: &Path,
+    url: &util::GsUrl,
+) -> anyhow::Result<(BTreeMap<String, LocalFile>, BTreeMap<String, Metadata>)> {
+    let local = {
+        let dir = dir.to_owned();
+        let progress = ctx.progress.clone();
+        async move {
+            tokio::task::spawn_blocking(move || list_local(&dir, &progress))
+                .await
+                .context("failed to join local listing")?
+        }
+    };
+
+    tokio::try_join!(local, list_remote(ctx, url))
+}
+
+/// The modification time of an object, in seconds since the Unix epoch. This
+/// is the modification time of the file it was uploaded from if it was
+/// recorded, otherwise the time the object was last updated
+fn object_mtime(md: &Metadata) -> Option<i64> {
+    md.metadata
+        .as_ref()
+        .and_then(|metadata| metadata.get(cp::MTIME_KEY)?.parse().ok())
+        .or_else(|| md.updated.map(time::OffsetDateTime::unix_timestamp))
+}
+
+/// Checks if the local file and the object differ. Sizes are always compared,
+/// followed by either the crc32c of the contents, or the modification times.
+/// Any difference in modification time counts, so that eg. a file restored to
+/// an older version is also synchronized
+async fn differs(local: &LocalFile, remote: &Metadata, checksum: bool) -> anyhow::Result<bool> {
+    if Some(local.size) != remote.size {
+        return Ok(true);
+    }
+
+    if checksum {
+        let Some(remote_crc) = remote.crc32c.as_deref() else {
+            return Ok(true);
+        };
+        let remote_crc = util::decode_crc32c(remote_crc)?;
+
+        let local_crc = {
+            let path = local.path.clone();
+            tokio::task::spawn_blocking(move || util::file_crc32c(&path)).await??
+        };
+
+        return Ok(local_crc != remote_crc);
+    }
+
+    let modified = time::OffsetDateTime::from(local.modified).unix_timestamp();
+    Ok(object_mtime(remote) != Some(modified))
+}
+
+/// Prints the outcome of synchronizing a single file or object, counting it
+/// as a failure if it failed, so that every failure is reported rather than
+/// only the first
+fn report(
+    ctx: &util::RequestContext,
+    failures: &AtomicUsize,
+    res: anyhow::Result<String>,
+) -> anyhow::Result<()> {
+    match res {
+        Ok(msg) => ctx.progress.println(msg),
+        Err(err) => {
+            ctx.progress.println(format!(
+                "{}\n  {}",
+                Color::Red.paint("ERR"),
+                Color::Red.paint(format!("{err:#}"))
+            ));
+            failures.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    Ok(())
+}
+
+async fn upload(
+    ctx: &util::RequestContext,
+    src: &Path,
+    dst: &util::GsUrl,
+    args: &Args,
+    failures: &AtomicUsize,
+) -> anyhow::Result<()> {
+    anyhow::ensure!(src.is_dir(), "'{}' is not a directory", src.display());
+
+    let (local, remote) = list_both(ctx, src, dst).await?;
+
+    let pairs = local
+        .iter()
+        .map(|(rel, file)| (rel, file, remote.get(rel)))
+        .collect();
+
+    cp::copy_all(args.jobs, pairs, |(rel, file, md)| async move {
+        let res = async {
+            if let Some(md) = md {
+                if !differs(file, md, args.checksum).await? {
+                    return anyhow::Ok(None);
+                }
+            }
+
+            let object = cp::join_object_name(dst.object(), Path::new(rel))?;
+            cp::upload(ctx, &file.path, dst.bucket(), &object, &args.opts).await?;
+
+            anyhow::Ok(Some(format!(
+                "{} {} -> gs://{}/{object}",
+                Color::Green.paint("Uploaded"),
+                file.path.display(),
+                dst.bucket()
+            )))
+        }
+        .await
+        .with_context(|| format!("failed to upload '{}'", file.path.display()));
+
+        match res.transpose() {
+            Some(res) => report(ctx, failures, res),
+            None => Ok(()),
+        }
+    })
+    .await?;
+
+    if args.delete {
+        let extraneous = remote
+            .iter()
+            .filter(|(rel, _)| !local.contains_key(*rel))
+            .collect();
+
+        cp::copy_all(args.jobs, extraneous, |(_rel, md)| async move {
+            let name = md.name.as_deref().unwrap_or_default();
+            let res = async {
+                let object = ObjectName::try_from(name)?;
+                // Only delete the object if it is still the generation that
+                // was listed, in case it was replaced in the meantime, which
+                // also allows the delete to be retried
+                let del_req = ctx.obj.delete(
+                    &(dst.bucket(), &object),
+                    Some(tame_gcs::objects::DeleteObjectOptional {
+                        conditionals: util::Preconditions {
+                            if_generation_match: md.generation,
+                            ..Default::default()
+                        }
+                        .conditionals(),
+                        ..Default::default()
+                    }),
+                )?;
+                util::execute::<_, tame_gcs::objects::DeleteObjectResponse>(ctx, del_req).await?;
+
+                anyhow::Ok(format!(
+                    "{} gs://{}/{name}",
+                    Color::Yellow.paint("Removed"),
+                    dst.bucket()
+                ))
+            }
+            .await
+            .with_context(|| format!("failed to remove '{name}'"));
+
+            report(ctx, failures, res)
+        })
+        .await?;
+    }
+
+    Ok(())
+}
+
+async fn download(
+    ctx: &util::RequestContext,
+    src: &util::GsUrl,
+    dst: &Path,
+    args: &Args,
+    failures: &AtomicUsize,
+) -> anyhow::Result<()> {
+    let (local, remote) = list_both(ctx, dst, src).await?;
+
+    let pairs = remote
+        .iter()
+        .map(|(rel, md)| (rel, md, local.get(rel)))
+        .collect();
+
+    cp::copy_all(args.jobs, pairs, |(rel, md, file)| async move {
+        let name = md.name.as_deref().unwrap_or_default();
+        let res = async {
+            if let Some(file) = file {
+                if !differs(file, md, args.checksum).await? {
+                    return anyhow::Ok(None);
+                }
+            }
+
+            let path = cp::local_path(dst, name, rel)?;
+
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent)
+                    .with_context(|| format!("failed to create '{}'", parent.display()))?;
+            }
+
+            let object = ObjectName::try_from(name)?;
+            cp::download(
+                ctx,
+                src.bucket(),
+                &object,
+                None,
+                Some(md),
+                &path,
+                &args.opts,
+            )
+            .await?;
+
+            // Give the file the modification time of the object, so that the
+            // next sync doesn't consider them different
+            if let Some(mtime) = object_mtime(md) {
+                filetime::set_file_mtime(&path, filetime::FileTime::from_unix_time(mtime, 0))
+                    .with_context(|| {
+                        format!("failed to set modification time of '{}'", path.display())
+                    })?;
+            }
+
+            anyhow::Ok(Some(format!(
+                "{} gs://{}/{name} -> {}",
+                Color::Green.paint("Downloaded"),
+                src.bucket(),
+                path.display()
+            )))
+        }
+        .await
+        .with_context(|| format!("failed to download '{name}'"));
+
+        match res.transpose() {
+            Some(res) => report(ctx, failures, res),
+            None => Ok(()),
+        }
+    })
+    .await?;
+
+    if args.delete {
+        let extraneous = local
+            .iter()
+            .filter(|(rel, _)| !remote.contains_key(*rel))
+            .collect();
+
+        cp::copy_all(args.jobs, extraneous, |(_rel, file)| async move {
+            let res = fs::remove_file(&file.path)
+                .map(|()| format!("{} {}", Color::Yellow.paint("Removed"), file.path.display()))
+                .with_context(|| format!("failed to remove '{}'", file.path.display()));
+
+            report(ctx, failures, res)
+        })
+        .await?;
+    }
+
+    Ok(())
+}
+
+/// Rejects the copy options that can't be used to synchronize
+fn check_options(opts: &cp::Options) -> anyhow::Result<()> {
+    // The size of a compressed object never matches the size of its source
+    // file, so every file would be uploaded again on every run
+    anyhow::ensure!(
+        !opts.is_compressed(),
+        "--gzip-local and --zstd are not supported by rsync"
+    );
+    // A skipped destination would still be reported as synchronized, and a
+    // download would be given the object's modification time despite having
+    // different contents, hiding the difference from every later sync
+    anyhow::ensure!(
+        !opts.is_no_clobber(),
+        "--no-clobber is not supported by rsync"
+    );
+
+    Ok(())
+}
+
+pub async fn run(ctx: &util::RequestContext, args: Args) -> anyhow::Result<()> {
+    check_options(&args.opts)?;
+
+    let parse = |s: &str| -> anyhow::Result<Option<util::GsUrl>> {
+        if s.starts_with("gs://") {
+            let url = url::Url::parse(s)?;
+            let gs_url = util::gs_url_to_object_id(&url)?;
+            anyhow::ensure!(
+                gs_url.generation().is_none(),
+                "'{s}' specifies a generation, which is not supported by rsync"
+            );
+            Ok(Some(gs_url))
+        } else {
+            Ok(None)
+        }
+    };
+
+    let failures = AtomicUsize::new(0);
+
+    match (parse(&args.src_url)?, parse(&args.dest_url)?) {
+        (None, Some(dst)) => upload(ctx, Path::new(&args.src_url), &dst, &args, &failures).await?,
+        (Some(src), None) => {
+            download(ctx, &src, Path::new(&args.dest_url), &args, &failures).await?;
+        }
+        (Some(_), Some(_)) => {
+            anyhow::bail!("synchronizing between two GCS locations is not supported")
+        }
+        (None, None) => anyhow::bail!("source and destination are both located on local disk"),
+    }
+
+    let failures = failures.into_inner();
+    anyhow::ensure!(failures == 0, "failed to synchronize {failures} files");
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser as _;
+
+    #[test]
+    fn rejects_unsupported_options() {
+        let check = |cmd: &str| {
+            let args = Args::try_parse_from(cmd.split_whitespace()).unwrap();
+            check_options(&args.opts)
+        };
+
+        assert!(check("rsync -d dir gs://bucket/dir").is_ok());
+        assert!(check("rsync -n dir gs://bucket/dir").is_err());
+        assert!(check("rsync --no-clobber gs://bucket/dir dir").is_err());
+        assert!(check("rsync --gzip-local dir gs://bucket/dir").is_err());
+        assert!(check("rsync --zstd dir gs://bucket/dir").is_err());
+    }
+
+    #[test]
+    fn prefers_recorded_mtime() {
+        let updated = time::macros::datetime!(2023-03-01 12:00 UTC);
+        let mut md = Metadata {
+            updated: Some(updated),
+            ..Default::default()
+        };
+
+        assert_eq!(object_mtime(&md), Some(updated.unix_timestamp()));
+
+        md.metadata =
+            Some(std::iter::once((cp::MTIME_KEY.to_owned(), "1600000000".to_owned())).collect());
+        assert_eq!(object_mtime(&md), Some(1_600_000_000));
+
+        md.metadata =
+            Some(std::iter::once((cp::MTIME_KEY.to_owned(), "bogus".to_owned())).collect());
+        assert_eq!(object_mtime(&md), Some(updated.unix_timestamp()));
+    }
+}
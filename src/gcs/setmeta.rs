@@ -0,0 +1,366 @@
+use super::{cp, encryption, util, wildcard};
+use anyhow::Context as _;
+use serde_json::{Map, Value};
+use tame_gcs::{
+    objects::{self, Metadata},
+    BucketName, ObjectName,
+};
+
+#[derive(clap::ValueEnum, Clone, Copy)]
+pub enum StorageClass {
+    Standard,
+    Nearline,
+    Coldline,
+    Archive,
+}
+
+impl StorageClass {
+    /// The name of the storage class as it appears in object resources
+    pub(super) fn as_str(self) -> &'static str {
+        match self {
+            Self::Standard => "STANDARD",
+            Self::Nearline => "NEARLINE",
+            Self::Coldline => "COLDLINE",
+            Self::Archive => "ARCHIVE",
+        }
+    }
+}
+
+/// Sets the metadata of one or more existing objects
+#[derive(clap::Parser)]
+#[clap(disable_help_flag = true)]
+pub struct Args {
+    /// Sets a metadata field via its header name, eg. `-h "Cache-Control:no-cache"`.
+    /// Supports Cache-Control, Content-Disposition, Content-Encoding,
+    /// Content-Language, Content-Type, Custom-Time, and `x-goog-meta-<key>`
+    /// for custom metadata. A header without a value, eg. `-h Cache-Control`,
+    /// removes the field
+    #[clap(short = 'h', long = "header")]
+    headers: Vec<String>,
+    /// Sets a custom metadata key, eg. `-m key=value`. A key without a value,
+    /// eg. `-m key=`, removes the key
+    #[clap(short = 'm', long = "metadata")]
+    metadata: Vec<String>,
+    /// Changes the storage class of the objects. Unlike the other fields, this
+    /// requires rewriting each object
+    #[clap(short = 's', long)]
+    storage_class: Option<StorageClass>,
+    #[clap(flatten)]
+    preconditions: util::Preconditions,
+    /// The keys used when changing the storage class, which rewrites objects
+    /// encrypted with a customer-supplied key (CSEK). Pass the object's key as
+    /// both `--decryption-key` and `--encryption-key` to keep it encrypted with
+    /// the same key
+    #[clap(flatten)]
+    encryption: encryption::Keys,
+    /// Print help
+    #[clap(long, action = clap::ArgAction::Help)]
+    help: Option<bool>,
+    /// One or more gs: URLs of the objects to update. Supports `*`, `**` and `?`
+    /// wildcards, in which case each match is updated. The metadata of a
+    /// specific generation of an object can be updated via
+    /// `gs://bucket/object#<generation>`, except for its storage class
+    #[clap(required = true)]
+    urls: Vec<String>,
+}
+
+/// The fields of an object resource that can be set by the user, all other
+/// fields are removed from the resource before it is used to rewrite an object
+const WRITABLE: &[&str] = &[
+    "cacheControl",
+    "contentDisposition",
+    "contentEncoding",
+    "contentLanguage",
+    "contentType",
+    "customTime",
+    "metadata",
+];
+
+/// The number of times a storage class change is attempted before giving up
+/// due to concurrent modifications
+const MAX_ATTEMPTS: usize = 5;
+
+/// Converts the headers and metadata arguments into the body of a PATCH
+/// request, where null values remove the field
+fn build_patch(headers: &[String], metadata: &[String]) -> anyhow::Result<Map<String, Value>> {
+    let to_value = |v: &str| {
+        if v.is_empty() {
+            Value::Null
+        } else {
+            Value::String(v.to_owned())
+        }
+    };
+
+    let mut patch = Map::new();
+    let mut custom = Map::new();
+
+    for header in headers {
+        let (name, value) = header
+            .split_once(':')
+            .map_or((header.as_str(), ""), |(name, value)| {
+                (name.trim(), value.trim())
+            });
+
+        let field = match name.to_ascii_lowercase().as_str() {
+            "cache-control" => "cacheControl",
+            "content-disposition" => "contentDisposition",
+            "content-encoding" => "contentEncoding",
+            "content-language" => "contentLanguage",
+            "content-type" => "contentType",
+            "custom-time" => "customTime",
+            lower if lower.starts_with("x-goog-meta-") => {
+                custom.insert(name["x-goog-meta-".len()..].to_owned(), to_value(value));
+                continue;
+            }
+            _ => anyhow::bail!("unsupported header '{name}'"),
+        };
+
+        patch.insert(field.to_owned(), to_value(value));
+    }
+
+    for kv in metadata {
+        let (key, value) = kv
+            .split_once('=')
+            .with_context(|| format!("metadata '{kv}' is not of the form key=value"))?;
+        custom.insert(key.to_owned(), to_value(value));
+    }
+
+    if !custom.is_empty() {
+        patch.insert("metadata".to_owned(), Value::Object(custom));
+    }
+
+    Ok(patch)
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn setmeta(
+    ctx: &util::RequestContext,
+    bucket: &BucketName<'_>,
+    object: &ObjectName<'_>,
+    generation: Option<i64>,
+    patch: &Map<String, Value>,
+    storage_class: Option<StorageClass>,
+    preconditions: util::Preconditions,
+    encryption: &encryption::Keys,
+) -> anyhow::Result<()> {
+    let Some(storage_class) = storage_class else {
+        let patch_req = ctx.obj.patch(
+            &(bucket, object),
+            &Metadata::default(),
+            Some(objects::PatchObjectOptional {
+                conditionals: preconditions.conditionals(),
+                ..Default::default()
+            }),
+        )?;
+        let patch_req = util::with_generation(patch_req, generation)?;
+        let patch_req = util::with_json_body(patch_req, &Value::Object(patch.clone()))?;
+        util::execute_json(ctx, patch_req).await?;
+        return Ok(());
+    };
+
+    // Rewriting a noncurrent generation would replace the live object with it
+    // rather than changing the storage class of that generation
+    anyhow::ensure!(
+        generation.is_none(),
+        "a specific generation can't be used with --storage-class"
+    );
+
+    // The storage class can only be changed by rewriting the object, which
+    // replaces the object's metadata with the metadata we send, so we need to
+    // apply the patch to the current metadata ourselves. The rewrite is only
+    // done if the object hasn't changed since we read it, otherwise we start
+    // over rather than losing a concurrent metadata change, or copying the
+    // old metadata onto a new generation of the object
+    for _ in 0..MAX_ATTEMPTS {
+        let get_req = ctx.obj.get(&(bucket, object), None)?;
+        let mut resource = util::execute_json(ctx, get_req).await?;
+
+        let read = util::Preconditions {
+            if_generation_match: Some(int_field(&resource, "generation")?),
+            if_metageneration_match: Some(int_field(&resource, "metageneration")?),
+        };
+
+        resource.retain(|key, _| WRITABLE.contains(&key.as_str()));
+        apply_patch(&mut resource, patch);
+        resource.insert(
+            "storageClass".to_owned(),
+            Value::String(storage_class.as_str().to_owned()),
+        );
+
+        // Guard the destination with the generation we read unless the user
+        // specified their own preconditions, which also makes the rewrite safe
+        // to retry
+        let user_preconditions = preconditions.if_generation_match.is_some()
+            || preconditions.if_metageneration_match.is_some();
+        let dst_preconditions = if user_preconditions {
+            preconditions
+        } else {
+            util::Preconditions {
+                if_generation_match: read.if_generation_match,
+                ..Default::default()
+            }
+        };
+
+        match cp::rewrite(
+            ctx,
+            (bucket, object),
+            None,
+            read,
+            (bucket, object),
+            Some(&Value::Object(resource)),
+            None,
+            dst_preconditions,
+            encryption,
+        )
+        .await
+        {
+            // A failure of the user's own preconditions is final
+            Err(err) if !user_preconditions && util::is_precondition_failure(&err) => continue,
+            res => return res,
+        }
+    }
+
+    anyhow::bail!("the object was concurrently modified {MAX_ATTEMPTS} times")
+}
+
+/// Retrieves an integer field of an object resource, which GCS encodes as a
+/// string
+fn int_field(resource: &Map<String, Value>, field: &str) -> anyhow::Result<i64> {
+    resource
+        .get(field)
+        .and_then(|value| value.as_str())
+        .and_then(|value| value.parse().ok())
+        .with_context(|| format!("object resource did not contain a {field}"))
+}
+
+/// Applies the patch to the writable fields of an object resource
+fn apply_patch(resource: &mut Map<String, Value>, patch: &Map<String, Value>) {
+    for (key, value) in patch {
+        match (key.as_str(), value) {
+            ("metadata", Value::Object(custom)) => {
+                let current = resource
+                    .entry("metadata")
+                    .or_insert_with(|| Value::Object(Map::new()));
+
+                if let Value::Object(current) = current {
+                    for (key, value) in custom {
+                        if value.is_null() {
+                            current.remove(key);
+                        } else {
+                            current.insert(key.clone(), value.clone());
+                        }
+                    }
+                }
+            }
+            (_, Value::Null) => {
+                resource.remove(key);
+            }
+            _ => {
+                resource.insert(key.clone(), value.clone());
+            }
+        }
+    }
+}
+
+pub async fn run(ctx: &util::RequestContext, args: Args) -> anyhow::Result<()> {
+    let patch = build_patch(&args.headers, &args.metadata)?;
+
+    anyhow::ensure!(
+        !patch.is_empty() || args.storage_class.is_some(),
+        "no metadata changes were specified"
+    );
+
+    for url in &args.urls {
+        let (bucket, names) = if wildcard::has_wildcard(url) {
+            let (bucket, pattern) = wildcard::parse_gs_pattern(url)?;
+            let names = wildcard::expand_gcs(ctx, &bucket, &pattern)
+                .await?
+                .into_iter()
+                .filter_map(|md| md.name.map(|name| (name, None)))
+                .collect();
+            (bucket, names)
+        } else {
+            let parsed = url::Url::parse(url)?;
+            let gs_url = util::gs_url_to_object_id(&parsed)?;
+            let object = gs_url
+                .object()
+                .with_context(|| format!("'{url}' is not an object"))?;
+
+            (
+                BucketName::try_from(gs_url.bucket().to_string())?,
+                vec![(object.to_string(), gs_url.generation())],
+            )
+        };
+
+        for (name, generation) in names {
+            let object = ObjectName::try_from(name.as_str())?;
+            let display = match generation {
+                Some(gen) => format!("gs://{bucket}/{name}#{gen}"),
+                None => format!("gs://{bucket}/{name}"),
+            };
+
+            setmeta(
+                ctx,
+                &bucket,
+                &object,
+                generation,
+                &patch,
+                args.storage_class,
+                args.preconditions,
+                &args.encryption,
+            )
+            .await
+            .with_context(|| format!("failed to update '{display}'"))?;
+
+            println!("Updated {display}");
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn patches_resource() {
+        let patch = build_patch(
+            &[
+                "Cache-Control:no-cache".to_owned(),
+                "Content-Language".to_owned(),
+            ],
+            &["a=".to_owned(), "c=3".to_owned()],
+        )
+        .unwrap();
+
+        let mut resource = json!({
+            "contentLanguage": "en",
+            "contentType": "text/plain",
+            "metadata": { "a": "1", "b": "2" },
+            "generation": "1680000000000000",
+            "metageneration": "3",
+        });
+        let resource = resource.as_object_mut().unwrap();
+
+        assert_eq!(
+            int_field(resource, "generation").unwrap(),
+            1_680_000_000_000_000
+        );
+        assert_eq!(int_field(resource, "metageneration").unwrap(), 3);
+        assert!(int_field(resource, "size").is_err());
+
+        resource.retain(|key, _| WRITABLE.contains(&key.as_str()));
+        apply_patch(resource, &patch);
+
+        assert_eq!(
+            Value::Object(resource.clone()),
+            json!({
+                "cacheControl": "no-cache",
+                "contentType": "text/plain",
+                "metadata": { "b": "2", "c": "3" },
+            })
+        );
+    }
+}
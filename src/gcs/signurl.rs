@@ -0,0 +1,85 @@
+use super::util;
+use anyhow::Context as _;
+use std::path::PathBuf;
+
+/// Generates V4 signed URLs that grant temporary access to objects without
+/// requiring the user of the URL to authenticate
+#[derive(clap::Parser)]
+pub struct Args {
+    /// How long the URL is valid for, eg. `30m`, `12h` or `7d`. The maximum is
+    /// 7 days
+    #[clap(short = 'd', long, default_value = "1h", value_parser = crate::parse_duration)]
+    duration: std::time::Duration,
+    /// The HTTP method the URL can be used with
+    #[clap(short = 'm', long, default_value = "GET", value_parser = parse_method)]
+    method: http::Method,
+    /// The service account key file used to sign the URL. Defaults to the
+    /// file pointed to by `GOOGLE_APPLICATION_CREDENTIALS`
+    #[clap(short = 'k', long)]
+    key_file: Option<PathBuf>,
+    /// One or more gs: URLs of the objects to sign. The objects don't need to
+    /// exist, eg. for URLs that are used to upload with `PUT`
+    #[clap(required = true)]
+    urls: Vec<String>,
+}
+
+fn parse_method(s: &str) -> Result<http::Method, String> {
+    match s.to_ascii_uppercase().as_str() {
+        "GET" => Ok(http::Method::GET),
+        "HEAD" => Ok(http::Method::HEAD),
+        "PUT" => Ok(http::Method::PUT),
+        "POST" => Ok(http::Method::POST),
+        "DELETE" => Ok(http::Method::DELETE),
+        _ => Err(format!("unsupported method `{s}`")),
+    }
+}
+
+/// Loads the service account key used to sign URLs. Only service accounts can
+/// sign URLs, so this fails for eg. gcloud's end user credentials
+fn load_key(key_file: Option<PathBuf>) -> anyhow::Result<tame_gcs::signing::ServiceAccount> {
+    let path = key_file
+        .or_else(|| std::env::var_os("GOOGLE_APPLICATION_CREDENTIALS").map(PathBuf::from))
+        .context(
+            "signing URLs requires a service account key, either via --key-file or GOOGLE_APPLICATION_CREDENTIALS",
+        )?;
+
+    tame_gcs::signing::ServiceAccount::load_json_file(&path).with_context(|| {
+        format!(
+            "failed to load service account key from '{}'",
+            path.display()
+        )
+    })
+}
+
+pub fn run(args: Args) -> anyhow::Result<()> {
+    let key = load_key(args.key_file)?;
+    let signer = tame_gcs::signed_url::UrlSigner::with_ring();
+
+    for url in &args.urls {
+        let parsed = url::Url::parse(url)?;
+        let gs_url = util::gs_url_to_object_id(&parsed)?;
+        let object = gs_url
+            .object()
+            .with_context(|| format!("'{url}' is not an object"))?;
+        anyhow::ensure!(
+            gs_url.generation().is_none(),
+            "'{url}' specifies a generation, which is not supported by signurl"
+        );
+
+        let signed = signer
+            .generate(
+                &key,
+                &(gs_url.bucket(), object),
+                tame_gcs::signed_url::SignedUrlOptional {
+                    method: args.method.clone(),
+                    duration: args.duration,
+                    ..Default::default()
+                },
+            )
+            .with_context(|| format!("failed to sign '{url}'"))?;
+
+        println!("{signed}");
+    }
+
+    Ok(())
+}
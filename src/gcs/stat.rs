@@ -0,0 +1,132 @@
+use super::{encryption, util, wildcard};
+use anyhow::Context as _;
+use tame_gcs::{objects, BucketName, ObjectName};
+
+/// Prints the metadata of one or more objects
+#[derive(clap::Parser)]
+pub struct Args {
+    /// Prints the full object resource as returned by GCS
+    #[clap(long)]
+    json: bool,
+    /// A base64 encoded AES-256 customer-supplied encryption key (CSEK) that
+    /// the objects are encrypted with, without which the hashes of encrypted
+    /// objects are not returned
+    #[clap(long)]
+    decryption_key: Option<encryption::Key>,
+    /// One or more gs: URLs of the objects to print. Supports `*`, `**` and `?`
+    /// wildcards, in which case each match is printed, and
+    /// `gs://bucket/object#<generation>` to print a specific generation
+    #[clap(required = true)]
+    urls: Vec<String>,
+}
+
+/// The fields that are printed when not outputting JSON, in the order they are
+/// printed, along with the label they are printed with
+const FIELDS: &[(&str, &str)] = &[
+    ("timeCreated", "Creation time"),
+    ("updated", "Update time"),
+    ("customTime", "Custom time"),
+    ("storageClass", "Storage class"),
+    ("size", "Content-Length"),
+    ("contentType", "Content-Type"),
+    ("contentEncoding", "Content-Encoding"),
+    ("contentDisposition", "Content-Disposition"),
+    ("contentLanguage", "Content-Language"),
+    ("cacheControl", "Cache-Control"),
+    ("crc32c", "Hash (crc32c)"),
+    ("md5Hash", "Hash (md5)"),
+    ("etag", "ETag"),
+    ("generation", "Generation"),
+    ("metageneration", "Metageneration"),
+];
+
+async fn stat(
+    ctx: &util::RequestContext,
+    bucket: &BucketName<'_>,
+    object: &ObjectName<'_>,
+    generation: Option<i64>,
+    json: bool,
+    key: Option<&encryption::Key>,
+) -> anyhow::Result<()> {
+    let get_req = encryption::with_key(
+        ctx.obj.get(
+            &(bucket, object),
+            Some(objects::GetObjectOptional {
+                generation,
+                ..Default::default()
+            }),
+        )?,
+        key,
+    );
+    let resource = util::execute_json(ctx, get_req)
+        .await
+        .with_context(|| format!("failed to retrieve 'gs://{bucket}/{object}'"))?;
+
+    if json {
+        println!("{}", serde_json::to_string_pretty(&resource)?);
+        return Ok(());
+    }
+
+    println!("gs://{bucket}/{object}:");
+
+    for (key, label) in FIELDS {
+        let value = match resource.get(*key) {
+            Some(serde_json::Value::String(s)) => s.clone(),
+            Some(serde_json::Value::Null) | None => continue,
+            Some(value) => value.to_string(),
+        };
+
+        println!("    {:<24}{value}", format!("{label}:"));
+    }
+
+    if let Some(serde_json::Value::Object(metadata)) = resource.get("metadata") {
+        println!("    Metadata:");
+
+        for (key, value) in metadata {
+            match value {
+                serde_json::Value::String(s) => println!("        {key}: {s}"),
+                value => println!("        {key}: {value}"),
+            }
+        }
+    }
+
+    Ok(())
+}
+
+pub async fn run(ctx: &util::RequestContext, args: Args) -> anyhow::Result<()> {
+    for url in &args.urls {
+        if wildcard::has_wildcard(url) {
+            let (bucket, pattern) = wildcard::parse_gs_pattern(url)?;
+
+            for md in wildcard::expand_gcs(ctx, &bucket, &pattern).await? {
+                let name = md.name.context("object metadata did not contain a name")?;
+                stat(
+                    ctx,
+                    &bucket,
+                    &ObjectName::try_from(name.as_str())?,
+                    None,
+                    args.json,
+                    args.decryption_key.as_ref(),
+                )
+                .await?;
+            }
+        } else {
+            let parsed = url::Url::parse(url)?;
+            let gs_url = util::gs_url_to_object_id(&parsed)?;
+
+            stat(
+                ctx,
+                gs_url.bucket(),
+                gs_url
+                    .object()
+                    .with_context(|| format!("'{url}' is not an object"))?,
+                gs_url.generation(),
+                args.json,
+                args.decryption_key.as_ref(),
+            )
+            .await?;
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,218 @@
+//! Bandwidth throttling of transfers. A single token bucket is shared by every
+//! request, regardless of whether it is sent from a tokio task or a rayon
+//! thread, so that the combined bandwidth of all concurrent transfers stays
+//! below the limit
+
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+/// The size of the pieces request bodies are sent in when throttled, so that
+/// large bodies are sent at a steady rate rather than in a single burst
+const CHUNK_SIZE: usize = 64 * 1024;
+/// The amount of time the bucket can accumulate tokens for while idle, which
+/// bounds the size of the burst after an idle period
+const BURST: Duration = Duration::from_millis(100);
+
+struct Bucket {
+    /// The number of bytes that can be transferred per second
+    rate: f64,
+    /// The maximum number of tokens the bucket can hold
+    capacity: f64,
+    /// The number of bytes that can be transferred immediately, which becomes
+    /// negative when transfers have reserved more than is currently available
+    tokens: f64,
+    last: Instant,
+}
+
+/// Limits the combined bandwidth of the transfers it is shared by. Cloning
+/// shares the same limit
+#[derive(Clone, Default)]
+pub struct Throttle {
+    bucket: Option<Arc<Mutex<Bucket>>>,
+}
+
+impl Throttle {
+    /// Creates a throttle that limits transfers to the specified number of
+    /// bytes per second, or doesn't limit them at all if `None`
+    pub fn new(max_bandwidth: Option<u64>) -> Self {
+        let bucket = max_bandwidth.map(|rate| {
+            let rate = rate as f64;
+            let capacity = (rate * BURST.as_secs_f64()).max(CHUNK_SIZE as f64);
+
+            Arc::new(Mutex::new(Bucket {
+                rate,
+                capacity,
+                tokens: capacity,
+                last: Instant::now(),
+            }))
+        });
+
+        Self { bucket }
+    }
+
+    #[inline]
+    pub fn is_enabled(&self) -> bool {
+        self.bucket.is_some()
+    }
+
+    /// Reserves the specified number of bytes, returning how long the caller
+    /// must wait before transferring them. Reservations are granted in the
+    /// order they are made, each one pushing back the ones that follow it
+    fn reserve(&self, bytes: u64) -> Option<Duration> {
+        let bucket = self.bucket.as_ref()?;
+        let mut bucket = bucket
+            .lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner);
+
+        let now = Instant::now();
+        let refill = now.duration_since(bucket.last).as_secs_f64() * bucket.rate;
+        bucket.tokens = (bucket.tokens + refill).min(bucket.capacity);
+        bucket.last = now;
+
+        bucket.tokens -= bytes as f64;
+
+        (bucket.tokens < 0.0).then(|| Duration::from_secs_f64(-bucket.tokens / bucket.rate))
+    }
+
+    /// Waits until the specified number of bytes can be transferred
+    pub async fn consume(&self, bytes: u64) {
+        if let Some(wait) = self.reserve(bytes) {
+            tokio::time::sleep(wait).await;
+        }
+    }
+
+    /// Blocks the current thread until the specified number of bytes can be
+    /// transferred
+    pub fn consume_blocking(&self, bytes: u64) {
+        if let Some(wait) = self.reserve(bytes) {
+            std::thread::sleep(wait);
+        }
+    }
+
+    /// Creates a request body that is streamed at the throttled rate
+    pub fn body(&self, data: bytes::Bytes) -> reqwest::Body {
+        let throttle = self.clone();
+
+        reqwest::Body::wrap_stream(futures_util::stream::unfold(data, move |mut data| {
+            let throttle = throttle.clone();
+            async move {
+                if data.is_empty() {
+                    return None;
+                }
+
+                let chunk = data.split_to(data.len().min(CHUNK_SIZE));
+                throttle.consume(chunk.len() as u64).await;
+                Some((Ok::<_, std::io::Error>(chunk), data))
+            }
+        }))
+    }
+
+    /// Creates a blocking request body that is sent at the throttled rate
+    pub fn blocking_body(&self, data: bytes::Bytes) -> reqwest::blocking::Body {
+        let len = data.len() as u64;
+        reqwest::blocking::Body::sized(self.reader(std::io::Cursor::new(data)), len)
+    }
+
+    /// Wraps a reader, eg. a blocking response, so that it is read at the
+    /// throttled rate
+    pub fn reader<R: std::io::Read>(&self, inner: R) -> Reader<R> {
+        Reader {
+            throttle: self.clone(),
+            inner,
+        }
+    }
+}
+
+/// A reader that waits for each piece it reads to be allowed through the
+/// throttle
+pub struct Reader<R> {
+    throttle: Throttle,
+    inner: R,
+}
+
+impl<R: std::io::Read> std::io::Read for Reader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let len = buf.len().min(CHUNK_SIZE);
+        let read = self.inner.read(&mut buf[..len])?;
+
+        self.throttle.consume_blocking(read as u64);
+        Ok(read)
+    }
+}
+
+/// Parses a bandwidth such as `50MiB/s`, or `50MiB`, into a number of bytes
+/// per second
+pub fn parse_bandwidth(s: &str) -> Result<u64, String> {
+    let size = s.trim();
+    let size = size.strip_suffix("/s").unwrap_or(size);
+
+    match crate::parse_byte_size(size)? {
+        0 => Err("bandwidth must be greater than 0".to_owned()),
+        bytes => Ok(bytes),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_about(wait: Option<Duration>, expected: f64) {
+        let wait = wait.expect("expected to wait").as_secs_f64();
+        assert!(
+            (wait - expected).abs() < 0.05,
+            "waited {wait}s rather than {expected}s"
+        );
+    }
+
+    #[test]
+    fn shares_limit_between_clones() {
+        assert!(Throttle::new(None).reserve(u64::MAX).is_none());
+
+        let rate = 1024 * 1024;
+        let throttle = Throttle::new(Some(rate));
+        let other = throttle.clone();
+
+        // The initial burst is allowed through immediately
+        assert!(throttle.reserve(rate / 10).is_none());
+
+        // After which each reservation waits for the ones before it, no matter
+        // which clone made them
+        assert_about(other.reserve(rate), 1.0);
+        assert_about(throttle.reserve(rate / 2), 1.5);
+    }
+
+    #[test]
+    fn reads_in_chunks() {
+        let data: Vec<u8> = (0..CHUNK_SIZE * 2 + 10).map(|i| i as u8).collect();
+        let mut reader = Throttle::new(Some(u64::MAX)).reader(std::io::Cursor::new(&data));
+
+        let mut buf = vec![0; data.len()];
+        assert_eq!(
+            std::io::Read::read(&mut reader, &mut buf).unwrap(),
+            CHUNK_SIZE
+        );
+
+        let mut read = Vec::new();
+        std::io::Read::read_to_end(&mut reader, &mut read).unwrap();
+        assert_eq!(&data[CHUNK_SIZE..], read.as_slice());
+    }
+
+    #[test]
+    fn parses_bandwidth() {
+        assert_eq!(parse_bandwidth("512").unwrap(), 512);
+        assert_eq!(parse_bandwidth("64KiB").unwrap(), 64 * 1024);
+        assert_eq!(parse_bandwidth("50MiB/s").unwrap(), 50 * 1024 * 1024);
+        assert_eq!(parse_bandwidth(" 2MB/s ").unwrap(), 2 * 1024 * 1024);
+    }
+
+    #[test]
+    fn rejects_invalid_bandwidth() {
+        assert!(parse_bandwidth("0").is_err());
+        assert!(parse_bandwidth("0MiB/s").is_err());
+        assert!(parse_bandwidth("fast").is_err());
+        assert!(parse_bandwidth("10PB/s").is_err());
+        assert!(parse_bandwidth("/s").is_err());
+    }
+}
@@ -1,17 +1,24 @@
 use anyhow::Context as _;
 use tame_gcs::{self as tgcs, http};
 
-/// Converts a vanilla `http::Request` into a `reqwest::Request`
+/// Converts a vanilla `http::Request` into a `reqwest::Request`, without its
+/// body, which is returned separately so that it can be shared by every
+/// attempt to send the request
 async fn convert_request<B>(
     req: http::Request<B>,
-    client: &reqwest::Client,
-) -> anyhow::Result<reqwest::Request>
+    ctx: &RequestContext,
+) -> anyhow::Result<(reqwest::Request, bytes::Bytes)>
 where
     B: std::io::Read + Send + 'static,
 {
-    let (parts, mut body) = req.into_parts();
+    let (parts, body) = req.into_parts();
 
-    let uri = parts.uri.to_string();
+    let uri = match &ctx.endpoint {
+        Some(endpoint) => endpoint.rewrite(parts.uri)?,
+        None => parts.uri,
+    }
+    .to_string();
+    let client = &ctx.client;
 
     let builder = match parts.method {
         http::Method::GET => client.get(&uri),
@@ -23,28 +30,36 @@ where
     };
 
     let content_len = tame_gcs::util::get_content_length(&parts.headers).unwrap_or(0);
-    let mut buffer = bytes::BytesMut::with_capacity(content_len);
+    let body = body_bytes(body, content_len)?;
 
-    let mut block = [0u8; 8 * 1024];
+    Ok((builder.headers(parts.headers).build()?, body))
+}
 
-    loop {
-        let read = body.read(&mut block)?;
+/// Reads a request body into a single buffer that can be cheaply cloned for
+/// each attempt to send the request. Bodies that are already a buffer in
+/// memory, eg. the chunks of a resumable upload, are taken as is rather than
+/// copied
+pub(crate) fn body_bytes<B>(mut body: B, content_len: usize) -> anyhow::Result<bytes::Bytes>
+where
+    B: std::io::Read + 'static,
+{
+    use std::any::Any;
 
-        if read > 0 {
-            buffer.extend_from_slice(&block[..read]);
-        } else {
-            break;
+    if let Some(cursor) = (&mut body as &mut dyn Any).downcast_mut::<std::io::Cursor<Vec<u8>>>() {
+        if cursor.position() == 0 {
+            return Ok(std::mem::take(cursor.get_mut()).into());
         }
     }
 
-    Ok(builder
-        .headers(parts.headers)
-        .body(buffer.freeze())
-        .build()?)
+    let mut buffer = Vec::with_capacity(content_len);
+    body.read_to_end(&mut buffer)
+        .context("failed to read request body")?;
+    Ok(buffer.into())
 }
 
-/// Converts a `reqwest::Response` into a vanilla `http::Response`. This currently copies
-/// the entire response body into a single buffer with no streaming
+/// Converts a `reqwest::Response` into a vanilla `http::Response`. This copies
+/// the entire response body into a single buffer, use [`execute_stream`] for
+/// responses that are too large to hold in memory
 async fn convert_response(res: reqwest::Response) -> anyhow::Result<http::Response<bytes::Bytes>> {
     let mut builder = http::Response::builder()
         .status(res.status())
@@ -79,6 +94,66 @@ async fn convert_response(res: reqwest::Response) -> anyhow::Result<http::Respon
 pub struct RequestContext {
     pub client: reqwest::Client,
     pub obj: tgcs::objects::Object,
+    /// The endpoint requests are sent to, if not GCS itself
+    pub endpoint: Option<super::Endpoint>,
+    pub retry: super::retry::Policy,
+    pub progress: super::progress::Progress,
+    pub throttle: super::throttle::Throttle,
+}
+
+/// Sends a GCS request via a reqwest client, retrying it if it failed with a
+/// retryable error and is idempotent. The final response is returned as is,
+/// regardless of its status
+async fn send<B>(ctx: &RequestContext, req: http::Request<B>) -> anyhow::Result<reqwest::Response>
+where
+    B: std::io::Read + Send + 'static,
+{
+    use super::retry;
+
+    let idempotent = retry::is_request_idempotent(&req);
+    let (request, body) = convert_request(req, ctx).await?;
+
+    let mut attempt = 1;
+    loop {
+        let mut attempt_req = request.try_clone().context("failed to clone request")?;
+
+        // Cloning the body only clones a reference to the same buffer
+        *attempt_req.body_mut() = Some(if ctx.throttle.is_enabled() && !body.is_empty() {
+            ctx.throttle.body(body.clone())
+        } else {
+            body.clone().into()
+        });
+
+        let res = ctx.client.execute(attempt_req).await;
+
+        let result = match &res {
+            Ok(res) => retry::Attempt::Response(res.status(), res.headers()),
+            Err(err) => retry::Attempt::Error(err),
+        };
+
+        match ctx.retry.next_attempt(attempt, idempotent, result) {
+            Some(delay) => {
+                tokio::time::sleep(delay).await;
+                attempt += 1;
+            }
+            None => return Ok(res?),
+        }
+    }
+}
+
+/// Executes a GCS request via a reqwest client and returns the response as is,
+/// for the cases where the status needs to be handled by the caller
+pub async fn execute_raw<B>(
+    ctx: &RequestContext,
+    req: http::Request<B>,
+) -> anyhow::Result<http::Response<bytes::Bytes>>
+where
+    B: std::io::Read + Send + 'static,
+{
+    let response = send(ctx, req).await?;
+    convert_response(response)
+        .await
+        .context("failed to convert response")
 }
 
 /// Executes a GCS request via a reqwest client and returns the parsed response/API error
@@ -87,18 +162,433 @@ where
     R: tgcs::ApiResponse<bytes::Bytes>,
     B: std::io::Read + Send + 'static,
 {
-    let request = convert_request(req, &ctx.client).await?;
-    let response = ctx.client.execute(request).await?;
+    let response = execute_raw(ctx, req).await?;
+
+    Ok(R::try_from_parts(response)?)
+}
+
+/// Executes a GCS request via a reqwest client, but rather than buffering the
+/// entire body, the response is returned as is so that the caller can stream
+/// the body in chunks as they arrive. Error responses are still buffered so
+/// that the API error can be returned
+pub async fn execute_stream<B>(
+    ctx: &RequestContext,
+    req: http::Request<B>,
+) -> anyhow::Result<reqwest::Response>
+where
+    B: std::io::Read + Send + 'static,
+{
+    let response = send(ctx, req).await?;
+
+    let status = response.status();
+    if status.is_success() {
+        return Ok(response);
+    }
+
     let response = convert_response(response)
         .await
         .context("failed to convert response")?;
 
-    Ok(R::try_from_parts(response)?)
+    Err(api_error(response))
+}
+
+/// Converts a failed response into the API error it contains
+pub fn api_error(response: http::Response<bytes::Bytes>) -> anyhow::Error {
+    let status = response.status();
+
+    // Every response type shares the same API error handling
+    use tgcs::ApiResponse;
+    match tgcs::objects::DownloadObjectResponse::try_from_parts(response) {
+        Ok(_) => anyhow::anyhow!("request failed with HTTP status {status}"),
+        Err(err) => err.into(),
+    }
+}
+
+/// Executes a GCS request and returns the JSON resource in the response body
+/// as is, for when the caller needs fields that tame-gcs doesn't know about
+pub async fn execute_json<B>(
+    ctx: &RequestContext,
+    req: http::Request<B>,
+) -> anyhow::Result<serde_json::Map<String, serde_json::Value>>
+where
+    B: std::io::Read + Send + 'static,
+{
+    let response = execute_raw(ctx, req).await?;
+
+    if !response.status().is_success() {
+        return Err(api_error(response));
+    }
+
+    serde_json::from_slice(response.body()).context("failed to deserialize JSON response")
+}
+
+/// Replaces the body of a request with a JSON document, for when the request
+/// needs fields that tame-gcs's `Metadata` doesn't know about
+pub fn with_json_body<B>(
+    req: http::Request<B>,
+    body: &serde_json::Value,
+) -> anyhow::Result<http::Request<std::io::Cursor<Vec<u8>>>> {
+    let (mut parts, _) = req.into_parts();
+    let body = serde_json::to_vec(body)?;
+
+    parts.headers.insert(
+        http::header::CONTENT_TYPE,
+        http::HeaderValue::from_static("application/json"),
+    );
+    parts
+        .headers
+        .insert(http::header::CONTENT_LENGTH, body.len().into());
+
+    Ok(http::Request::from_parts(parts, std::io::Cursor::new(body)))
+}
+
+/// Targets a specific generation of an object with a request whose tame-gcs
+/// optional parameters don't support one, eg. a PATCH
+pub fn with_generation<B>(
+    req: http::Request<B>,
+    generation: Option<i64>,
+) -> anyhow::Result<http::Request<B>> {
+    let Some(generation) = generation else {
+        return Ok(req);
+    };
+
+    let (mut parts, body) = req.into_parts();
+    let sep = if parts.uri.query().is_some() {
+        '&'
+    } else {
+        '?'
+    };
+    parts.uri = format!("{}{sep}generation={generation}", parts.uri)
+        .parse()
+        .context("failed to add generation to url")?;
+
+    Ok(http::Request::from_parts(parts, body))
+}
+
+/// Creates a multipart upload request. This builds the same request as
+/// `Object::insert_multipart`, but with a body that includes the metadata
+/// fields that tame-gcs's `Metadata` doesn't support.
+///
+/// Only the url and headers are taken from tame-gcs, the body is framed here
+/// with the same `tame_gcs` boundary that tame-gcs (0.12) puts in the
+/// `multipart/related` content type header, and which it doesn't expose. This
+/// must be kept in sync if tame-gcs ever changes its boundary
+pub fn insert_multipart<B>(
+    ctx: &RequestContext,
+    bucket: &tgcs::BucketName<'_>,
+    content: B,
+    length: u64,
+    metadata: &super::resumable::UploadMetadata,
+    optional: Option<tgcs::objects::InsertObjectOptional<'_>>,
+) -> anyhow::Result<http::Request<impl std::io::Read + Send + 'static>>
+where
+    B: std::io::Read + Send + 'static,
+{
+    use std::io::Read;
+
+    const SUFFIX: &[u8] = b"\n--tame_gcs--";
+
+    let (mut parts, _) = ctx
+        .obj
+        .insert_multipart(bucket, std::io::empty(), 0, &metadata.metadata, optional)?
+        .into_parts();
+
+    let mut prefix = b"--tame_gcs\ncontent-type: application/json; charset=utf-8\n\n".to_vec();
+    serde_json::to_writer(&mut prefix, metadata)?;
+    prefix.extend_from_slice(
+        format!(
+            "\n--tame_gcs\ncontent-type: {}\n\n",
+            metadata
+                .metadata
+                .content_type
+                .as_deref()
+                .unwrap_or("application/octet-stream")
+        )
+        .as_bytes(),
+    );
+
+    parts.headers.insert(
+        http::header::CONTENT_LENGTH,
+        (prefix.len() as u64 + length + SUFFIX.len() as u64).into(),
+    );
+
+    Ok(http::Request::from_parts(
+        parts,
+        std::io::Cursor::new(prefix).chain(content).chain(SUFFIX),
+    ))
+}
+
+/// Creates the url for an object operation that tame-gcs doesn't implement, eg.
+/// `compose`, using the same authority and object name encoding that it does
+pub fn object_url(
+    ctx: &RequestContext,
+    bucket: &tgcs::BucketName<'_>,
+    object: &tgcs::ObjectName<'_>,
+    operation: &str,
+) -> anyhow::Result<String> {
+    let get_req = ctx.obj.get(&(bucket, object), None)?;
+    let uri = get_req.uri();
+
+    Ok(format!(
+        "{}://{}{}/{operation}",
+        uri.scheme_str().unwrap_or("https"),
+        uri.authority().context("object url has no authority")?,
+        uri.path()
+    ))
+}
+
+/// Creates the url for a bucket operation, which tame-gcs doesn't implement,
+/// using the same authority as object operations. A `None` bucket refers to
+/// the collection of buckets itself, eg. for creating or listing buckets
+pub fn bucket_url(
+    ctx: &RequestContext,
+    bucket: Option<&tgcs::BucketName<'_>>,
+) -> anyhow::Result<String> {
+    let placeholder = tgcs::BucketName::non_validated("_");
+    let list_req = ctx.obj.list(bucket.unwrap_or(&placeholder), None)?;
+    let uri = list_req.uri();
+
+    // Object listings are at `/storage/v1/b/<bucket>/o`
+    let path = uri
+        .path()
+        .strip_suffix("/o")
+        .context("unexpected object listing url")?;
+    let path = match bucket {
+        Some(_) => path,
+        None => path
+            .rsplit_once('/')
+            .map_or(path, |(collection, _)| collection),
+    };
+
+    Ok(format!(
+        "{}://{}{path}",
+        uri.scheme_str().unwrap_or("https"),
+        uri.authority().context("bucket url has no authority")?,
+    ))
+}
+
+/// The objects, and "directories", from a listing
+pub struct Listing {
+    pub objects: Vec<tgcs::objects::Metadata>,
+    /// The prefixes that were rolled up due to the delimiter, ie. the names of
+    /// the "directories" beneath the listing's prefix
+    pub prefixes: Vec<String>,
+}
+
+/// Lists the objects in a bucket whose name starts with the specified prefix,
+/// paging through the results until the listing is exhausted. If a delimiter
+/// is specified, objects whose name contain the delimiter after the prefix are
+/// rolled up into [`Listing::prefixes`] rather than being returned
+pub async fn list(
+    ctx: &RequestContext,
+    bucket: &tgcs::BucketName<'_>,
+    prefix: Option<&str>,
+    delimiter: Option<&str>,
+) -> anyhow::Result<Listing> {
+    let mut listing = Listing {
+        objects: Vec::new(),
+        prefixes: Vec::new(),
+    };
+    let mut page_token: Option<String> = None;
+
+    loop {
+        let list_req = ctx.obj.list(
+            bucket,
+            Some(tgcs::objects::ListOptional {
+                prefix,
+                delimiter,
+                page_token: page_token.as_deref(),
+                ..Default::default()
+            }),
+        )?;
+
+        let res: tgcs::objects::ListResponse = execute(ctx, list_req).await?;
+        listing.objects.extend(res.objects);
+        listing.prefixes.extend(res.prefixes);
+
+        page_token = res.page_token;
+        if page_token.is_none() {
+            break;
+        }
+    }
+
+    Ok(listing)
+}
+
+/// Lists every object in a bucket whose name starts with the specified prefix
+pub async fn list_objects(
+    ctx: &RequestContext,
+    bucket: &tgcs::BucketName<'_>,
+    prefix: Option<&str>,
+) -> anyhow::Result<Vec<tgcs::objects::Metadata>> {
+    Ok(list(ctx, bucket, prefix, None).await?.objects)
+}
+
+/// Preconditions on the current state of an object that must be met for an
+/// operation that modifies it to proceed
+#[derive(clap::Parser, Clone, Copy, Default)]
+pub struct Preconditions {
+    /// Only modifies the object if its current generation matches. 0 means
+    /// that the object must not exist
+    #[clap(long)]
+    pub if_generation_match: Option<i64>,
+    /// Only modifies the object if its current metageneration matches
+    #[clap(long)]
+    pub if_metageneration_match: Option<i64>,
+}
+
+impl Preconditions {
+    #[inline]
+    pub fn conditionals(self) -> tgcs::common::Conditionals {
+        tgcs::common::Conditionals {
+            if_generation_match: self.if_generation_match,
+            if_metageneration_match: self.if_metageneration_match,
+            ..Default::default()
+        }
+    }
+}
+
+/// Returns true if the error is due to a precondition not being met
+pub fn is_precondition_failure(err: &anyhow::Error) -> bool {
+    err.chain()
+        .any(|err| match err.downcast_ref::<tgcs::Error>() {
+            Some(tgcs::Error::HttpStatus(status)) => {
+                status.0 == http::StatusCode::PRECONDITION_FAILED
+            }
+            Some(tgcs::Error::Api(api)) => api.code == http::StatusCode::PRECONDITION_FAILED,
+            _ => false,
+        })
+}
+
+/// Decodes the base64 encoded, big-endian, crc32c from an object's metadata
+pub fn decode_crc32c(encoded: &str) -> anyhow::Result<u32> {
+    use base64::Engine;
+
+    let bytes = base64::engine::general_purpose::STANDARD
+        .decode(encoded)
+        .with_context(|| format!("invalid crc32c '{encoded}'"))?;
+
+    Ok(u32::from_be_bytes(
+        bytes
+            .as_slice()
+            .try_into()
+            .with_context(|| format!("invalid crc32c '{encoded}'"))?,
+    ))
+}
+
+/// Decodes the base64 encoded md5 from an object's metadata
+pub fn decode_md5(encoded: &str) -> anyhow::Result<Vec<u8>> {
+    use base64::Engine;
+
+    let bytes = base64::engine::general_purpose::STANDARD
+        .decode(encoded)
+        .with_context(|| format!("invalid md5 '{encoded}'"))?;
+
+    anyhow::ensure!(bytes.len() == 16, "invalid md5 '{encoded}'");
+    Ok(bytes)
+}
+
+/// The hashes of an object's data sent with a download response
+#[derive(Default)]
+pub struct Hashes {
+    pub crc32c: Option<u32>,
+    /// Only used if the object doesn't have a crc32c, which GCS calculates for
+    /// every object, but may be missing from objects uploaded by eg. an old
+    /// client or an emulator
+    pub md5: Option<Vec<u8>>,
+    /// True if the object was decompressed by GCS before being sent, in which
+    /// case there are no hashes, as they are for the compressed data
+    pub transcoded: bool,
+}
+
+/// Retrieves the hashes of the object from the `x-goog-hash` header(s) of a
+/// download response. There won't be any if the object was decompressed by
+/// GCS before being sent, as the hashes are for the compressed data that is
+/// stored, see [`Hashes::transcoded`]
+pub fn response_hashes(headers: &http::HeaderMap) -> anyhow::Result<Hashes> {
+    let stored_encoding = headers
+        .get("x-goog-stored-content-encoding")
+        .and_then(|hv| hv.to_str().ok());
+    let encoding = headers
+        .get(http::header::CONTENT_ENCODING)
+        .and_then(|hv| hv.to_str().ok());
+
+    let mut hashes = Hashes::default();
+
+    if stored_encoding.map_or(false, |se| se != "identity") && stored_encoding != encoding {
+        hashes.transcoded = true;
+        return Ok(hashes);
+    }
+
+    for hv in headers.get_all("x-goog-hash") {
+        let Ok(values) = hv.to_str() else {
+            continue;
+        };
+
+        for hash in values.split(',') {
+            let hash = hash.trim();
+
+            if let Some(crc) = hash.strip_prefix("crc32c=") {
+                hashes.crc32c = Some(decode_crc32c(crc)?);
+            } else if let Some(md5) = hash.strip_prefix("md5=") {
+                hashes.md5 = Some(decode_md5(md5)?);
+            }
+        }
+    }
+
+    Ok(hashes)
+}
+
+/// Calculates the crc32c of a file's contents
+pub fn file_crc32c(path: &std::path::Path) -> anyhow::Result<u32> {
+    use std::io::Read;
+
+    let mut file = std::fs::File::open(path)
+        .with_context(|| format!("failed to open '{}'", path.display()))?;
+
+    let mut block = vec![0u8; 64 * 1024];
+    let mut crc = 0;
+
+    loop {
+        let read = file.read(&mut block)?;
+
+        if read > 0 {
+            crc = crc32c::crc32c_append(crc, &block[..read]);
+        } else {
+            break;
+        }
+    }
+
+    Ok(crc)
+}
+
+/// Calculates the md5 of a file's contents
+pub fn file_md5(path: &std::path::Path) -> anyhow::Result<Vec<u8>> {
+    use std::io::Read;
+
+    let mut file = std::fs::File::open(path)
+        .with_context(|| format!("failed to open '{}'", path.display()))?;
+
+    let mut block = vec![0u8; 64 * 1024];
+    let mut md5 = openssl::hash::Hasher::new(openssl::hash::MessageDigest::md5())?;
+
+    loop {
+        let read = file.read(&mut block)?;
+
+        if read > 0 {
+            md5.update(&block[..read])?;
+        } else {
+            break;
+        }
+    }
+
+    Ok(md5.finish()?.to_vec())
 }
 
 pub struct GsUrl {
     bucket_name: tgcs::BucketName<'static>,
     obj_name: Option<tgcs::ObjectName<'static>>,
+    generation: Option<i64>,
 }
 
 impl GsUrl {
@@ -109,21 +599,124 @@ impl GsUrl {
     pub fn object(&self) -> Option<&tame_gcs::ObjectName<'_>> {
         self.obj_name.as_ref()
     }
+
+    /// The specific generation of the object, if one was specified
+    pub fn generation(&self) -> Option<i64> {
+        self.generation
+    }
 }
 
-/// Converts a `gs://<bucket_name>/<object_name>` url into a regular object identifer
+/// Converts a `gs://<bucket_name>/<object_name>[#<generation>]` url into a
+/// regular object identifer
 pub fn gs_url_to_object_id(url: &url::Url) -> anyhow::Result<GsUrl> {
     match url.scheme() {
         "gs" => {
             let bucket_name = url.host_str().context("no bucket specified")?;
-            // Skip first /
-            let object_name = &url.path()[1..];
+            // Skip first /, which isn't present at all for eg. `gs://bucket`
+            let object_name = url.path().strip_prefix('/').unwrap_or_default();
+
+            let generation = url
+                .fragment()
+                .map(|gen| {
+                    gen.parse()
+                        .with_context(|| format!("invalid object generation '{gen}'"))
+                })
+                .transpose()?;
 
             Ok(GsUrl {
                 bucket_name: tame_gcs::BucketName::try_from(String::from(bucket_name))?,
                 obj_name: tame_gcs::ObjectName::try_from(String::from(object_name)).ok(),
+                generation,
             })
         }
         scheme => anyhow::bail!("invalid url scheme: {scheme}"),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn takes_buffered_bodies() {
+        let data = vec![7u8; 1024];
+        let ptr = data.as_ptr();
+
+        let body = body_bytes(std::io::Cursor::new(data), 1024).unwrap();
+        assert_eq!(body.as_ptr(), ptr);
+        assert_eq!(body.len(), 1024);
+
+        let mut cursor = std::io::Cursor::new(vec![1u8, 2, 3, 4]);
+        cursor.set_position(2);
+        assert_eq!(body_bytes(cursor, 0).unwrap().as_ref(), [3, 4]);
+
+        let chained = std::io::Read::chain(&b"ab"[..], &b"cd"[..]);
+        assert_eq!(body_bytes(chained, 4).unwrap().as_ref(), b"abcd");
+    }
+
+    #[test]
+    fn detects_transcoding() {
+        let headers = |pairs: &[(&'static str, &'static str)]| {
+            let mut headers = http::HeaderMap::new();
+            for (name, value) in pairs {
+                headers.append(*name, http::HeaderValue::from_static(value));
+            }
+            headers
+        };
+
+        let hashes = response_hashes(&headers(&[
+            ("x-goog-stored-content-encoding", "gzip"),
+            ("x-goog-hash", "crc32c=n03x6A=="),
+        ]))
+        .unwrap();
+        assert!(hashes.transcoded);
+        assert!(hashes.crc32c.is_none());
+
+        let hashes = response_hashes(&headers(&[
+            ("x-goog-stored-content-encoding", "gzip"),
+            ("content-encoding", "gzip"),
+            (
+                "x-goog-hash",
+                "crc32c=n03x6A==,md5=XrY7u+Ae7tCTyyK7j1rNww==",
+            ),
+        ]))
+        .unwrap();
+        assert!(!hashes.transcoded);
+        assert_eq!(hashes.crc32c, Some(0x9f4df1e8));
+        assert!(hashes.md5.is_some());
+
+        let hashes =
+            response_hashes(&headers(&[("x-goog-stored-content-encoding", "identity")])).unwrap();
+        assert!(!hashes.transcoded);
+        assert!(hashes.crc32c.is_none());
+    }
+
+    #[test]
+    fn adds_generation() {
+        let obj = tgcs::objects::Object::default();
+        let id = (
+            &tgcs::BucketName::non_validated("bucket"),
+            &tgcs::ObjectName::non_validated("object"),
+        );
+        let patch = |generation| {
+            let req = obj
+                .patch(&id, &tgcs::objects::Metadata::default(), None)
+                .unwrap();
+            with_generation(req, generation).unwrap().uri().clone()
+        };
+
+        assert_eq!(patch(None).query(), Some("prettyPrint=false"));
+        assert_eq!(
+            patch(Some(12)).query(),
+            Some("prettyPrint=false&generation=12")
+        );
+
+        let req = http::Request::get("https://storage.googleapis.com/storage/v1/b/bucket/o/object")
+            .body(())
+            .unwrap();
+        assert_eq!(
+            with_generation(req, Some(3)).unwrap().uri().query(),
+            Some("generation=3")
+        );
+    }
+}
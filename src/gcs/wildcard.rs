@@ -0,0 +1,296 @@
+//! gsutil style [wildcards](https://cloud.google.com/storage/docs/wildcards)
+//!
+//! * `*` matches zero or more characters within the current path segment
+//! * `**` matches zero or more characters, including path separators
+//! * `?` matches a single character other than a path separator
+
+use super::util;
+use anyhow::Context as _;
+use std::path::PathBuf;
+use tame_gcs::{objects::Metadata, BucketName};
+
+/// Returns true if the string contains any wildcard characters
+#[inline]
+pub fn has_wildcard(s: &str) -> bool {
+    s.contains(['*', '?'])
+}
+
+/// Returns the portion of the pattern before the first wildcard character
+#[inline]
+pub fn literal_prefix(pattern: &str) -> &str {
+    pattern
+        .find(['*', '?'])
+        .map_or(pattern, |ind| &pattern[..ind])
+}
+
+/// Returns the portion of a name that matched the pattern, relative to the
+/// deepest directory of the pattern that doesn't contain a wildcard, eg.
+/// `a/b/c.pdb` for `dir/a/b/c.pdb` matched by `dir/**/*.pdb`
+#[inline]
+pub fn relative_name<'n>(pattern: &str, name: &'n str) -> &'n str {
+    let dir_len = literal_prefix(pattern).rfind('/').map_or(0, |ind| ind + 1);
+    name.get(dir_len..).unwrap_or(name)
+}
+
+/// Checks if the text matches the wildcard pattern in its entirety
+///
+/// This is the usual iterative glob match, which only remembers the last `*`
+/// and the last `**` to backtrack to rather than every one of them, as a later
+/// wildcard can always match anything an earlier one could have. For that to
+/// hold, `**/` matches zero or more whole directories, so `a**/b` matches
+/// `ax/b`, but not `ab`
+pub fn matches(pattern: &str, text: &str) -> bool {
+    let p: Vec<_> = pattern.chars().collect();
+    let t: Vec<_> = text.chars().collect();
+
+    // The position just past the next path separator, ie. the start of the
+    // next directory
+    let next_dir = |from: usize| {
+        t[from..]
+            .iter()
+            .position(|tc| *tc == '/')
+            .map(|i| from + i + 1)
+    };
+
+    let (mut pi, mut ti) = (0, 0);
+    // The pattern position after the last `*`, and the text position it
+    // currently matches up to
+    let mut star: Option<(usize, usize)> = None;
+    // The same for the last `**`, and whether it was followed by a `/`, in
+    // which case it matches whole directories, including none at all, so that
+    // eg. `dir/**/*.pdb` matches `dir/a.pdb` as well as `dir/b/c.pdb`
+    let mut globstar: Option<(usize, usize, bool)> = None;
+
+    loop {
+        if p.get(pi) == Some(&'*') {
+            if p.get(pi + 1) == Some(&'*') {
+                let dirs = p.get(pi + 2) == Some(&'/');
+                pi += if dirs { 3 } else { 2 };
+
+                if dirs && ti > 0 && t[ti - 1] != '/' {
+                    // Not at the start of a directory, so there must be at
+                    // least one separator for `**/` to match
+                    let Some(next) = next_dir(ti) else {
+                        return false;
+                    };
+                    ti = next;
+                }
+
+                globstar = Some((pi, ti, dirs));
+                star = None;
+            } else {
+                pi += 1;
+                star = Some((pi, ti));
+            }
+
+            continue;
+        }
+
+        match (p.get(pi), t.get(ti)) {
+            (None, None) => return true,
+            (Some(pc), Some(tc)) if pc == tc || (*pc == '?' && *tc != '/') => {
+                pi += 1;
+                ti += 1;
+                continue;
+            }
+            _ => {}
+        }
+
+        // `*` can consume one more character, as long as it stays within the
+        // current path segment
+        if let Some((sp, st)) = star {
+            if t.get(st).map_or(false, |tc| *tc != '/') {
+                star = Some((sp, st + 1));
+                (pi, ti) = (sp, st + 1);
+                continue;
+            }
+        }
+
+        // Otherwise `**` consumes one more character, or one more directory
+        if let Some((gp, gt, dirs)) = globstar {
+            let next = if dirs {
+                next_dir(gt)
+            } else {
+                (gt < t.len()).then_some(gt + 1)
+            };
+
+            if let Some(next) = next {
+                globstar = Some((gp, next, dirs));
+                star = None;
+                (pi, ti) = (gp, next);
+                continue;
+            }
+        }
+
+        return false;
+    }
+}
+
+/// Splits a `gs://<bucket>/<pattern>` url into the bucket and object pattern.
+/// This is done manually rather than via `url::Url` as `?` would otherwise
+/// be treated as the start of the query string
+pub fn parse_gs_pattern(s: &str) -> anyhow::Result<(BucketName<'static>, String)> {
+    let rest = s.strip_prefix("gs://").context("invalid url scheme")?;
+    let (bucket, pattern) = rest
+        .split_once('/')
+        .context("wildcards are not supported in bucket names")?;
+
+    anyhow::ensure!(
+        !has_wildcard(bucket),
+        "wildcards are not supported in bucket names"
+    );
+
+    Ok((BucketName::try_from(bucket.to_owned())?, pattern.to_owned()))
+}
+
+/// Expands a wildcard pattern into every local file that matches it, along
+/// with its path relative to the deepest directory of the pattern that doesn't
+/// contain a wildcard
+pub fn expand_local(pattern: &str) -> anyhow::Result<Vec<(PathBuf, String)>> {
+    let pattern = if cfg!(windows) {
+        pattern.replace('\\', "/")
+    } else {
+        pattern.to_owned()
+    };
+
+    // Only walk from the deepest directory that doesn't contain a wildcard
+    let (base, rest) = match literal_prefix(&pattern).rfind('/') {
+        Some(ind) => (&pattern[..ind], &pattern[ind + 1..]),
+        None => (".", pattern.as_str()),
+    };
+
+    // A pattern such as `/*.pdb` has a base of the root directory
+    let base = std::path::Path::new(if base.is_empty() { "/" } else { base });
+
+    let mut walker = walkdir::WalkDir::new(base).min_depth(1);
+
+    // Without a recursive wildcard we know exactly how deep to go
+    if !rest.contains("**") {
+        walker = walker.max_depth(rest.split('/').count());
+    }
+
+    let mut matched = Vec::new();
+
+    for entry in walker {
+        let entry = entry.context("failed to walk directory")?;
+
+        if !entry.file_type().is_file() {
+            continue;
+        }
+
+        let rel = entry
+            .path()
+            .strip_prefix(base)?
+            .components()
+            .map(|comp| comp.as_os_str().to_str())
+            .collect::<Option<Vec<_>>>()
+            .with_context(|| format!("path '{}' is not utf-8", entry.path().display()))?
+            .join("/");
+
+        if matches(rest, &rel) {
+            matched.push((entry.into_path(), rel));
+        }
+    }
+
+    anyhow::ensure!(!matched.is_empty(), "no files matched '{pattern}'");
+
+    matched.sort();
+    Ok(matched)
+}
+
+/// Expands a wildcard pattern into every object in the bucket that matches it,
+/// by listing all of the objects that share the pattern's literal prefix and
+/// matching the remainder locally
+pub async fn expand_gcs(
+    ctx: &util::RequestContext,
+    bucket: &BucketName<'_>,
+    pattern: &str,
+) -> anyhow::Result<Vec<Metadata>> {
+    let prefix = literal_prefix(pattern);
+
+    let matched: Vec<_> = util::list_objects(ctx, bucket, (!prefix.is_empty()).then_some(prefix))
+        .await?
+        .into_iter()
+        .filter(|md| {
+            md.name
+                .as_deref()
+                .map_or(false, |name| matches(pattern, name))
+        })
+        .collect();
+
+    anyhow::ensure!(
+        !matched.is_empty(),
+        "no objects matched 'gs://{bucket}/{pattern}'"
+    );
+
+    Ok(matched)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn single_star_stays_within_segment() {
+        assert!(matches("*.pdb", "a.pdb"));
+        assert!(matches("*.pdb", ".pdb"));
+        assert!(!matches("*.pdb", "dir/a.pdb"));
+        assert!(matches("dir/*/a.pdb", "dir/x/a.pdb"));
+        assert!(!matches("dir/*/a.pdb", "dir/x/y/a.pdb"));
+        assert!(!matches("dir/*", "dir/x/a.pdb"));
+    }
+
+    #[test]
+    fn double_star_crosses_segments() {
+        assert!(matches("dir/**", "dir/a.pdb"));
+        assert!(matches("dir/**", "dir/x/y/a.pdb"));
+        assert!(matches("dir/**/*.pdb", "dir/a.pdb"));
+        assert!(matches("dir/**/*.pdb", "dir/x/y/a.pdb"));
+        assert!(!matches("dir/**/*.pdb", "dir/x/y/a.exe"));
+        assert!(matches("**", "a/b/c"));
+        assert!(!matches("dir/**", "other/a.pdb"));
+        assert!(matches("a**/b", "ax/y/b"));
+        assert!(!matches("a**/b", "ab"));
+    }
+
+    #[test]
+    fn question_mark_matches_one_char() {
+        assert!(matches("a?.pdb", "ab.pdb"));
+        assert!(!matches("a?.pdb", "a.pdb"));
+        assert!(!matches("a?.pdb", "abc.pdb"));
+        assert!(!matches("a?b", "a/b"));
+    }
+
+    #[test]
+    fn pathological_patterns() {
+        let text = "a".repeat(200);
+
+        assert!(!matches("a*a*a*a*a*a*a*a*a*a*b", &text));
+        assert!(matches("a*a*a*a*a*a*a*a*a*a*a", &text));
+        assert!(!matches("**a**a**a**a**a**a**a**a**b", &text));
+
+        let nested = format!("{}/b", ["a"; 100].join("/"));
+        assert!(!matches("**/a/**/a/**/a/**/a/**/c", &nested));
+        assert!(matches("**/a/**/a/**/a/**/a/**/b", &nested));
+        assert!(!matches("*/*/*/*/*/*/*/*/*/*/c", &nested));
+    }
+
+    #[test]
+    fn literal() {
+        assert!(matches("dir/a.pdb", "dir/a.pdb"));
+        assert!(!matches("dir/a.pdb", "dir/a.pdbx"));
+
+        assert_eq!(literal_prefix("dir/sub/*.pdb"), "dir/sub/");
+        assert_eq!(literal_prefix("dir/a?.pdb"), "dir/a");
+        assert_eq!(literal_prefix("**"), "");
+        assert_eq!(literal_prefix("dir/a.pdb"), "dir/a.pdb");
+    }
+
+    #[test]
+    fn relative_names() {
+        assert_eq!(relative_name("dir/*.pdb", "dir/a.pdb"), "a.pdb");
+        assert_eq!(relative_name("dir/**/*.pdb", "dir/x/y/a.pdb"), "x/y/a.pdb");
+        assert_eq!(relative_name("dir/a*/*.pdb", "dir/ab/c.pdb"), "ab/c.pdb");
+        assert_eq!(relative_name("*.pdb", "a.pdb"), "a.pdb");
+    }
+}
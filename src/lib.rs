@@ -8,6 +8,63 @@ pub trait Scopes {
     fn scopes(&self) -> &'static [&'static str];
 }
 
+/// Parses a human friendly byte size such as `512`, `64KiB` or `8MB`. Note that
+/// all units are treated as powers of 2, regardless of whether they are written
+/// as eg. `MB` or `MiB`
+pub fn parse_byte_size(s: &str) -> Result<u64, String> {
+    let s = s.trim();
+    let (num, unit) = s.split_at(s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len()));
+
+    let num: u64 = num
+        .parse()
+        .map_err(|err| format!("`{s}` isn't a valid size {err}"))?;
+
+    let multiplier: u64 = match unit.trim() {
+        "" | "B" => 1,
+        "K" | "KB" | "KiB" => 1 << 10,
+        "M" | "MB" | "MiB" => 1 << 20,
+        "G" | "GB" | "GiB" => 1 << 30,
+        "T" | "TB" | "TiB" => 1 << 40,
+        unit => return Err(format!("unknown size unit `{unit}`")),
+    };
+
+    num.checked_mul(multiplier)
+        .ok_or_else(|| format!("`{s}` is too large"))
+}
+
+/// Formats a number of bytes with a binary unit, eg. `2.00 MiB`
+pub fn bytes_to_human(bytes: u64) -> String {
+    use number_prefix::NumberPrefix;
+
+    match NumberPrefix::binary(bytes as f64) {
+        NumberPrefix::Standalone(bytes) => format!("{bytes} B"),
+        NumberPrefix::Prefixed(prefix, n) => format!("{n:.2} {prefix}B"),
+    }
+}
+
+/// Parses a human friendly duration such as `90`, `30m`, `12h` or `7d`, where
+/// a number without a unit is treated as seconds
+pub fn parse_duration(s: &str) -> Result<std::time::Duration, String> {
+    let s = s.trim();
+    let (num, unit) = s.split_at(s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len()));
+
+    let num: u64 = num
+        .parse()
+        .map_err(|err| format!("`{s}` isn't a valid duration {err}"))?;
+
+    let multiplier: u64 = match unit.trim() {
+        "" | "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        unit => return Err(format!("unknown duration unit `{unit}`")),
+    };
+
+    num.checked_mul(multiplier)
+        .map(std::time::Duration::from_secs)
+        .ok_or_else(|| format!("`{s}` is too large"))
+}
+
 pub async fn get_bearer_token(scopes: &[&str]) -> anyhow::Result<http::header::HeaderValue> {
     use anyhow::Context as _;
     use gcp::TokenProvider;
@@ -4,9 +4,16 @@ use clap::Parser;
 
 #[derive(Parser)]
 #[clap(author, version, about)]
-enum Args {
-    Artifact(boh::artifact::Args),
+struct Args {
     #[clap(subcommand)]
+    cmd: Command,
+}
+
+// Only ever constructed once, so boxing the larger variants gains nothing
+#[allow(clippy::large_enum_variant)]
+#[derive(clap::Subcommand)]
+enum Command {
+    Artifact(boh::artifact::Args),
     Gcs(boh::gcs::Args),
     #[clap(subcommand)]
     Kms(boh::kms::Args),
@@ -18,41 +25,54 @@ enum Args {
 async fn main() -> anyhow::Result<()> {
     let args = Args::parse();
 
-    let scopes = match &args {
-        Args::Artifact(a) => a.scopes(),
-        Args::Gcs(a) => a.scopes(),
-        Args::Kms(a) => a.scopes(),
-        Args::Kubectl(a) => a.scopes(),
-        Args::Syms(a) => a.scopes(),
+    let scopes = match &args.cmd {
+        Command::Artifact(a) => a.scopes(),
+        Command::Gcs(a) => a.scopes(),
+        Command::Kms(a) => a.scopes(),
+        Command::Kubectl(a) => a.scopes(),
+        Command::Syms(a) => a.scopes(),
     };
 
-    // Get a token for the default credentials on the system
-    let auth_token = boh::get_bearer_token(scopes).await?;
+    // Get a token for the default credentials on the system, unless the
+    // command doesn't make any authenticated requests
+    let auth_token = if scopes.is_empty() {
+        None
+    } else {
+        Some(boh::get_bearer_token(scopes).await?)
+    };
 
     let hm = {
         let mut hm = reqwest::header::HeaderMap::new();
-        hm.insert(http::header::AUTHORIZATION, auth_token.clone());
+        if let Some(auth_token) = &auth_token {
+            hm.insert(http::header::AUTHORIZATION, auth_token.clone());
+        }
         hm
     };
 
     let client_builder = reqwest::Client::builder().default_headers(hm);
 
-    match args {
-        Args::Artifact(gcs) => boh::artifact::run(gcs, client_builder).await?,
-        Args::Gcs(gcs) => boh::gcs::run(gcs, client_builder).await?,
-        Args::Kms(kms) => boh::kms::run(kms, client_builder).await?,
-        Args::Kubectl(kube) => boh::kubectl::run(kube, client_builder).await?,
-        Args::Syms(syms) => {
+    match args.cmd {
+        Command::Artifact(gcs) => boh::artifact::run(gcs, client_builder).await?,
+        Command::Gcs(gcs) => boh::gcs::run(gcs, client_builder).await?,
+        Command::Kms(kms) => boh::kms::run(kms, client_builder).await?,
+        Command::Kubectl(kube) => boh::kubectl::run(kube, client_builder).await?,
+        Command::Syms(syms) => {
             let hm = {
                 let mut hm = reqwest::header::HeaderMap::new();
-                hm.insert(http::header::AUTHORIZATION, auth_token);
+                if let Some(auth_token) = auth_token {
+                    hm.insert(http::header::AUTHORIZATION, auth_token);
+                }
                 hm
             };
 
-            let client = reqwest::blocking::Client::builder()
-                .default_headers(hm)
-                .build()
-                .context("failed to build client")?;
+            // The blocking client spins up its own runtime, which panics if
+            // done on a runtime thread that isn't allowed to block
+            let client = tokio::task::block_in_place(|| {
+                reqwest::blocking::Client::builder()
+                    .default_headers(hm)
+                    .build()
+            })
+            .context("failed to build client")?;
 
             boh::syms::run(syms, client).await?
         }
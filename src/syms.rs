@@ -1,3 +1,4 @@
+use crate::gcs::retry;
 use anyhow::Context as _;
 pub use camino::Utf8PathBuf as PathBuf;
 use clap::Parser;
@@ -75,61 +76,224 @@ fn create_source_bundle(name: &str, obj: &Object<'_>) -> anyhow::Result<Vec<u8>>
 
 use std::time::Duration;
 
+/// The kinds of files that are stored for each debug or code identifier
+#[derive(clap::ValueEnum, Clone, Copy)]
+pub enum Kind {
+    Debuginfo,
+    Executable,
+    Sourcebundle,
+}
+
+impl Kind {
+    /// The name of the object the kind of file is stored as
+    fn suffix(self) -> &'static str {
+        match self {
+            Self::Debuginfo => "debuginfo",
+            Self::Executable => "executable",
+            Self::Sourcebundle => "sourcebundle",
+        }
+    }
+}
+
+/// The path of the object a kind of file with the identifier is stored at,
+/// which follows the layout Sentry's symbol server lookup expects
+#[inline]
+fn object_path(prefix: &gcs::ObjectName<'_>, id: &str, kind: Kind) -> PathBuf {
+    format!("{prefix}/{}/{}/{}", &id[..2], &id[2..], kind.suffix()).into()
+}
+
 struct Ctx {
     client: Client,
     bucket: gcs::BucketName<'static>,
     prefix: gcs::ObjectName<'static>,
     gcs: gcs::objects::Object,
+    /// The endpoint requests are sent to, if not GCS itself
+    endpoint: Option<crate::gcs::Endpoint>,
+    retry: retry::Policy,
+    progress: crate::gcs::progress::Progress,
+    throttle: crate::gcs::throttle::Throttle,
     compression_level: i32,
     bundle_sources: bool,
+    resumable_threshold: u64,
+    no_clobber: bool,
 }
 
 impl Ctx {
-    fn upload(&self, metadata: Metadata, content: Vec<u8>) -> anyhow::Result<()> {
-        let len = content.len() as u64;
+    fn new(
+        client: Client,
+        bucket: String,
+        mut path: String,
+        gcs_opts: crate::gcs::Options,
+    ) -> anyhow::Result<Self> {
+        while path.ends_with('/') {
+            path.pop();
+        }
 
-        let req = self.gcs.insert_multipart(
-            &self.bucket,
-            std::io::Cursor::new(content),
-            len,
-            &metadata,
-            None,
-        )?;
+        let bucket: gcs::BucketName<'static> =
+            bucket.try_into().context("invalid gcs bucket name")?;
+        let prefix: gcs::ObjectName<'static> = path.try_into().context("invalid gcs path")?;
+
+        Ok(Self {
+            client,
+            bucket,
+            prefix,
+            gcs: gcs_opts
+                .gcs_endpoint
+                .as_ref()
+                .map_or_else(gcs::objects::Object::default, crate::gcs::Endpoint::object),
+            endpoint: gcs_opts.gcs_endpoint,
+            retry: retry::Policy::new(gcs_opts.gcs_max_attempts),
+            progress: crate::gcs::progress::Progress::new(),
+            throttle: crate::gcs::throttle::Throttle::new(gcs_opts.max_bandwidth),
+            compression_level: 0,
+            bundle_sources: false,
+            resumable_threshold: u64::MAX,
+            no_clobber: false,
+        })
+    }
+
+    /// Sends a request and converts the response, without checking its status.
+    /// Retryable failures are retried if the request is idempotent
+    fn send<B: std::io::Read + 'static>(
+        &self,
+        req: http::Request<B>,
+    ) -> anyhow::Result<http::Response<bytes::Bytes>> {
+        let res = self.execute(req)?;
+
+        let mut builder = http::Response::builder()
+            .status(res.status())
+            .version(res.version());
+
+        let headers = builder
+            .headers_mut()
+            .context("failed to convert response headers")?;
+
+        headers.extend(
+            res.headers()
+                .into_iter()
+                .map(|(k, v)| (k.clone(), v.clone())),
+        );
 
-        let (req, mut body) = req.into_parts();
+        let body = res.bytes().context("failed to receive body")?;
 
-        let buf = Vec::with_capacity(len as usize + 1024);
-        let mut cursor = std::io::Cursor::new(buf);
-        std::io::copy(&mut body, &mut cursor)?;
+        Ok(builder.body(body)?)
+    }
+
+    /// Sends a request, retrying retryable failures if the request is
+    /// idempotent, and returns the response as is so that its body can be
+    /// streamed
+    fn execute<B: std::io::Read + 'static>(
+        &self,
+        req: http::Request<B>,
+    ) -> anyhow::Result<reqwest::blocking::Response> {
+        let idempotent = retry::is_request_idempotent(&req);
+        let (req, body) = req.into_parts();
+
+        let len = gcs::util::get_content_length(&req.headers).unwrap_or_default();
+        let body = crate::gcs::util::body_bytes(body, len)?;
+
+        let uri = match &self.endpoint {
+            Some(endpoint) => endpoint.rewrite(req.uri)?,
+            None => req.uri,
+        };
 
-        let rb = self.client.request(req.method, req.uri.to_string());
+        let rb = self.client.request(req.method, uri.to_string());
         let req = rb
             .headers(req.headers)
-            .body(cursor.into_inner())
+            .body(body.clone())
             .build()
             .context("failed to build request")?;
 
-        let res = {
-            let res = self.client.execute(req).context("failed to send request")?;
+        let mut attempt = 1;
+        loop {
+            let mut attempt_req = req.try_clone().context("failed to clone request")?;
+
+            if self.throttle.is_enabled() && !body.is_empty() {
+                *attempt_req.body_mut() = Some(self.throttle.blocking_body(body.clone()));
+            }
+
+            let res = self.client.execute(attempt_req);
+
+            let result = match &res {
+                Ok(res) => retry::Attempt::Response(res.status(), res.headers()),
+                Err(err) => retry::Attempt::Error(err),
+            };
+
+            match self.retry.next_attempt(attempt, idempotent, result) {
+                Some(delay) => {
+                    std::thread::sleep(delay);
+                    attempt += 1;
+                }
+                None => return res.context("failed to send request"),
+            }
+        }
+    }
+
+    /// In no-clobber mode symbols are only uploaded if they don't already
+    /// exist, which also makes the upload safe to retry, as a retry after a
+    /// lost response can't overwrite an object written by someone else in the
+    /// meantime. Otherwise existing symbols are overwritten, and the upload is
+    /// not retried
+    #[inline]
+    fn insert_optional(&self) -> Option<gcs::objects::InsertObjectOptional<'static>> {
+        self.no_clobber.then(|| gcs::objects::InsertObjectOptional {
+            conditionals: gcs::common::Conditionals {
+                if_generation_match: Some(0),
+                ..Default::default()
+            },
+            ..Default::default()
+        })
+    }
 
-            let mut builder = http::Response::builder()
-                .status(res.status())
-                .version(res.version());
+    /// Checks if an upload failed because the object already exists in
+    /// no-clobber mode, in which case it is skipped
+    fn already_uploaded(
+        &self,
+        res: &http::Response<bytes::Bytes>,
+        name: &str,
+        transfer: &crate::gcs::progress::Transfer,
+        len: u64,
+    ) -> bool {
+        if !self.no_clobber || res.status() != http::StatusCode::PRECONDITION_FAILED {
+            return false;
+        }
 
-            let headers = builder
-                .headers_mut()
-                .context("failed to convert response headers")?;
+        self.progress
+            .println(format!("Skipping existing 'gs://{}/{name}'", self.bucket));
+        transfer.inc(len);
+        true
+    }
 
-            headers.extend(
-                res.headers()
-                    .into_iter()
-                    .map(|(k, v)| (k.clone(), v.clone())),
+    fn upload(&self, metadata: Metadata, content: Vec<u8>) -> anyhow::Result<()> {
+        let len = content.len() as u64;
+        let transfer = self
+            .progress
+            .transfer(metadata.name.clone().unwrap_or_default(), Some(len));
+
+        if len > self.resumable_threshold {
+            return self.upload_resumable(
+                &crate::gcs::resumable::UploadMetadata {
+                    metadata,
+                    ..Default::default()
+                },
+                &content,
+                &transfer,
             );
+        }
 
-            let body = res.bytes().context("failed to receive body")?;
+        let name = metadata.name.clone().unwrap_or_default();
+        let req = self.gcs.insert_multipart(
+            &self.bucket,
+            std::io::Cursor::new(content),
+            len,
+            &metadata,
+            self.insert_optional(),
+        )?;
 
-            builder.body(body)?
-        };
+        let res = self.send(req)?;
+        if self.already_uploaded(&res, &name, &transfer, len) {
+            return Ok(());
+        }
 
         use gcs::ApiResponse;
         if res.status().is_success() {
@@ -154,9 +318,129 @@ impl Ctx {
             }
         }
 
+        transfer.inc(len);
         Ok(())
     }
 
+    /// Uploads the content in chunks via a resumable upload session, resuming
+    /// from the last offset committed by GCS if a chunk fails
+    fn upload_resumable(
+        &self,
+        metadata: &crate::gcs::resumable::UploadMetadata,
+        content: &[u8],
+        transfer: &crate::gcs::progress::Transfer,
+    ) -> anyhow::Result<()> {
+        use crate::gcs::resumable;
+
+        let len = content.len() as u64;
+        let init_req =
+            resumable::init_request(&self.gcs, &self.bucket, metadata, self.insert_optional())?;
+
+        let init_res = self.send(init_req)?;
+        let name = metadata.metadata.name.as_deref().unwrap_or_default();
+        if self.already_uploaded(&init_res, name, transfer, len) {
+            return Ok(());
+        }
+
+        let mut upload = resumable::Upload::new(init_res, len)?;
+
+        loop {
+            let req = if upload.needs_status() {
+                upload.status_request()?
+            } else {
+                let range = upload.next_chunk();
+                upload.chunk_request(content[range.start as usize..range.end as usize].to_vec())?
+            };
+            let res = self.send(req);
+
+            let committed = upload.offset();
+            let step = upload.handle_result(res, &self.retry)?;
+            transfer.inc(upload.offset().saturating_sub(committed));
+
+            match step {
+                resumable::Step::Complete(_) => return Ok(()),
+                resumable::Step::Next => {}
+                resumable::Step::Resume(delay) => std::thread::sleep(delay),
+            }
+        }
+    }
+
+    /// Downloads the object and decompresses it to the output file, returning
+    /// the size of the decompressed file. The compressed data is verified
+    /// against the crc32c GCS calculated for the object, and the output file is
+    /// removed if it doesn't match
+    fn fetch(&self, name: &str, output: &std::path::Path) -> anyhow::Result<u64> {
+        use std::io::{Read, Write};
+
+        let object = gcs::ObjectName::try_from(name)?;
+        let dl_req = self.gcs.download(&(&self.bucket, &object), None)?;
+        let res = self.execute(dl_req)?;
+
+        let status = res.status();
+        if status == http::StatusCode::NOT_FOUND {
+            anyhow::bail!("'gs://{}/{name}' does not exist", self.bucket);
+        } else if !status.is_success() {
+            anyhow::bail!(
+                "request failed: HTTP status: {status} -> {}",
+                res.text().unwrap_or_default()
+            );
+        }
+
+        let expected_crc = crate::gcs::util::response_hashes(res.headers())?.crc32c;
+        let transfer = self
+            .progress
+            .transfer(format!("gs://{}/{name}", self.bucket), res.content_length());
+
+        let write = || -> anyhow::Result<u64> {
+            let file = std::fs::File::create(output)
+                .with_context(|| format!("failed to create '{}'", output.display()))?;
+            let mut decoder = zstd::stream::write::Decoder::new(std::io::BufWriter::new(file))
+                .context("failed to create decoder")?;
+
+            let mut res = self.throttle.reader(res);
+            let mut chunk = vec![0u8; 64 * 1024];
+            let mut actual_crc = 0;
+
+            loop {
+                let read = res.read(&mut chunk).context("failed to receive body")?;
+                if read == 0 {
+                    break;
+                }
+
+                actual_crc = crc32c::crc32c_append(actual_crc, &chunk[..read]);
+                decoder
+                    .write_all(&chunk[..read])
+                    .context("failed to decompress")?;
+                transfer.inc(read as u64);
+            }
+
+            decoder.flush()?;
+            decoder.into_inner().flush()?;
+
+            match expected_crc {
+                Some(expected_crc) => anyhow::ensure!(
+                    expected_crc == actual_crc,
+                    "crc32c mismatch, expected {expected_crc:08x} but downloaded data was {actual_crc:08x}"
+                ),
+                None => self.progress.warn(format!(
+                    "'gs://{}/{name}' has no crc32c hash, the downloaded data was not verified",
+                    self.bucket
+                )),
+            }
+
+            Ok(std::fs::metadata(output)?.len())
+        };
+
+        let res = write();
+
+        // Don't leave a partial file behind
+        if res.is_err() {
+            let _ = std::fs::remove_file(output);
+        }
+
+        res
+    }
+
     #[inline]
     fn compress(&self, input: &[u8]) -> anyhow::Result<Vec<u8>> {
         zstd::encode_all(input, self.compression_level).context("failed to compress")
@@ -167,14 +451,18 @@ impl Ctx {
         let id = get_unified_id(obj)?;
 
         #[allow(clippy::wildcard_enum_match_arm)]
-        let suffix = match obj.kind() {
-            ObjectKind::Debug => "debuginfo",
-            ObjectKind::Sources if obj.file_format() == FileFormat::SourceBundle => "sourcebundle",
-            ObjectKind::Relocatable | ObjectKind::Library | ObjectKind::Executable => "executable",
+        let kind = match obj.kind() {
+            ObjectKind::Debug => Kind::Debuginfo,
+            ObjectKind::Sources if obj.file_format() == FileFormat::SourceBundle => {
+                Kind::Sourcebundle
+            }
+            ObjectKind::Relocatable | ObjectKind::Library | ObjectKind::Executable => {
+                Kind::Executable
+            }
             _ => anyhow::bail!("unsupported file"),
         };
 
-        let path = format!("{}/{}/{}/{suffix}", self.prefix, &id[..2], &id[2..]).into();
+        let path = object_path(&self.prefix, &id, kind);
         Ok((id, path))
     }
 
@@ -325,28 +613,24 @@ pub struct FileStat {
     pub objects: anyhow::Result<Vec<anyhow::Result<ObjectStat>>>,
 }
 
+#[allow(clippy::too_many_arguments)]
 pub fn upload(
     client: Client,
     bucket: String,
-    mut path: String,
+    path: String,
     compression_level: i32,
     bundle_sources: bool,
+    resumable_threshold: u64,
+    no_clobber: bool,
     objects: Vec<ObjectFile>,
+    gcs_opts: crate::gcs::Options,
 ) -> anyhow::Result<Vec<FileStat>> {
-    while path.ends_with('/') {
-        path.pop();
-    }
-
-    let bucket: gcs::BucketName<'static> = bucket.try_into().context("invalid gcs bucket name")?;
-    let prefix: gcs::ObjectName<'static> = path.try_into().context("invalid gcs path")?;
-
     let ctx = Ctx {
-        client,
-        bucket,
-        prefix,
         compression_level,
         bundle_sources,
-        gcs: gcs::objects::Object::default(),
+        resumable_threshold,
+        no_clobber,
+        ..Ctx::new(client, bucket, path, gcs_opts)?
     };
 
     Ok(objects
@@ -391,23 +675,83 @@ fn level_in_range(s: &str) -> Result<i32, String> {
     }
 }
 
-/// Uploads debug symbols to GCS
+/// Where symbols are stored, which can be specified either before or after
+/// `fetch`
+#[derive(Parser)]
+pub struct Location {
+    /// GCS bucket to upload symbols to, or fetch them from
+    #[arg(long, global = true, env = "SYMS_BUCKET")]
+    bucket: Option<String>,
+    /// The path within the bucket that symbols are stored beneath
+    #[arg(long, global = true, env = "SYMS_PATH")]
+    path: Option<String>,
+}
+
+impl Location {
+    fn resolve(self) -> anyhow::Result<(String, String)> {
+        let bucket = self
+            .bucket
+            .context("a bucket must be specified via --bucket or SYMS_BUCKET")?;
+        let path = self
+            .path
+            .context("a path must be specified via --path or SYMS_PATH")?;
+
+        Ok((bucket, path))
+    }
+}
+
+/// Downloads a debug file uploaded by `syms` and decompresses it
+#[derive(Parser)]
+pub struct FetchArgs {
+    /// The kind of file to fetch
+    #[arg(long, value_enum, default_value = "debuginfo")]
+    kind: Kind,
+    /// The path to write the decompressed file to
+    #[arg(short, long)]
+    output: std::path::PathBuf,
+    /// The code identifier of the file, or its debug identifier if it is a PE
+    /// file or doesn't have a code identifier. Debug identifiers can be in
+    /// either their breakpad or UUID form
+    id: String,
+}
+
+#[derive(clap::Subcommand)]
+pub enum Command {
+    Fetch(FetchArgs),
+}
+
+/// The compression level used if `--compression-level` isn't specified
+const DEFAULT_COMPRESSION_LEVEL: i32 = 5;
+
+/// Uploads debug symbols to GCS, or fetches previously uploaded ones via
+/// `syms fetch`. The upload arguments require the directories to upload, so
+/// they can't be combined with a subcommand
 #[derive(Parser)]
 pub struct Args {
-    /// GCS bucket to upload symbols to
-    #[arg(long, env = "SYMS_BUCKET")]
-    bucket: String,
-    #[arg(long, env = "SYMS_PATH")]
-    path: String,
+    #[clap(subcommand)]
+    cmd: Option<Command>,
+    #[clap(flatten)]
+    location: Location,
+    #[clap(flatten)]
+    gcs: crate::gcs::Options,
     /// Creates source bindles and includes them in the upload
-    #[arg(long)]
+    #[arg(long, requires = "dirs")]
     bundle_sources: bool,
     /// The ZSTD compression level to use when compressing objects before upload
-    #[arg(long, short, default_value = "5", value_parser = level_in_range)]
+    #[arg(long, short, default_value_t = DEFAULT_COMPRESSION_LEVEL, value_parser = level_in_range, requires = "dirs")]
     compression_level: i32,
+    /// Objects larger than this size after compression are uploaded in chunks
+    /// via resumable uploads, so that a failed request doesn't restart the
+    /// entire upload
+    #[arg(long, default_value = "8MiB", value_parser = crate::parse_byte_size, requires = "dirs")]
+    resumable_threshold: u64,
+    /// Skips symbols that have already been uploaded rather than overwriting
+    /// them. This also allows uploads to be retried if they fail
+    #[arg(long, requires = "dirs")]
+    no_clobber: bool,
     /// If set, _any_ failure to parse or upload symbols will cause the command
     /// to fail, even if some succeeded
-    #[arg(long)]
+    #[arg(long, requires = "dirs")]
     strict: bool,
     /// Directories to find symbols in
     dirs: Vec<PathBuf>,
@@ -415,11 +759,81 @@ pub struct Args {
 
 impl crate::Scopes for Args {
     fn scopes(&self) -> &'static [&'static str] {
+        // Emulators don't authenticate requests, and the user might not even
+        // have credentials, eg. in CI
+        if self.gcs.gcs_endpoint.is_some() {
+            return &[];
+        }
+
         &["https://www.googleapis.com/auth/devstorage.full_control"]
     }
 }
 
+/// Normalizes an identifier to the form objects are stored under
+fn normalize_id(id: &str) -> anyhow::Result<String> {
+    // Code identifiers never contain dashes, so this can only be a debug
+    // identifier in its UUID form, eg. as displayed by Sentry
+    let id = if id.contains('-') {
+        let debug_id: symbolic_common::DebugId = id
+            .parse()
+            .with_context(|| format!("'{id}' is not a valid debug identifier"))?;
+        debug_id.breakpad().to_string()
+    } else {
+        id.to_owned()
+    }
+    .to_lowercase();
+
+    anyhow::ensure!(
+        id.len() > 2 && id.chars().all(|c| c.is_ascii_hexdigit()),
+        "'{id}' is not a valid debug or code identifier"
+    );
+
+    Ok(id)
+}
+
+pub fn fetch(
+    client: Client,
+    location: Location,
+    args: FetchArgs,
+    gcs_opts: crate::gcs::Options,
+) -> anyhow::Result<()> {
+    let (bucket, path) = location.resolve()?;
+    let ctx = Ctx::new(client, bucket, path, gcs_opts)?;
+
+    let id = normalize_id(&args.id)?;
+    let name = object_path(&ctx.prefix, &id, args.kind);
+
+    let size = ctx
+        .fetch(name.as_str(), &args.output)
+        .with_context(|| format!("failed to fetch '{id}'"))?;
+
+    use nu_ansi_term::{Color, Style};
+
+    println!(
+        "{} gs://{}/{name} -> {} {}",
+        Color::Green.paint("Fetched"),
+        ctx.bucket,
+        args.output.display(),
+        Style::default()
+            .dimmed()
+            .paint(format!("({})", crate::bytes_to_human(size))),
+    );
+
+    Ok(())
+}
+
 pub async fn run(args: Args, client: reqwest::blocking::Client) -> anyhow::Result<()> {
+    if let Some(Command::Fetch(fetch_args)) = args.cmd {
+        let (location, gcs_opts) = (args.location, args.gcs);
+        return tokio::task::spawn_blocking(move || fetch(client, location, fetch_args, gcs_opts))
+            .await
+            .context("failed to join fetch")?;
+    }
+
+    let gcs_opts = args.gcs;
+
+    let (bucket, path) = args.location.resolve()?;
+
     let objects = gather_objects(args.dirs);
     anyhow::ensure!(
         !objects.is_empty(),
@@ -428,11 +842,14 @@ pub async fn run(args: Args, client: reqwest::blocking::Client) -> anyhow::Resul
 
     let stats = upload(
         client,
-        args.bucket,
-        args.path,
+        bucket,
+        path,
         args.compression_level,
         args.bundle_sources,
+        args.resumable_threshold,
+        args.no_clobber,
         objects,
+        gcs_opts,
     )?;
 
     use nu_ansi_term::{Color, Style};
@@ -527,3 +944,88 @@ pub async fn run(args: Args, client: reqwest::blocking::Client) -> anyhow::Resul
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn normalizes_ids() {
+        // Breakpad debug identifier, as used in the symbol store
+        assert_eq!(
+            normalize_id("DFB8E43AF2423D73A453AEB6A777EF750").unwrap(),
+            "dfb8e43af2423d73a453aeb6a777ef750"
+        );
+        // Code identifiers, eg. a PE timestamp + size or an ELF build id
+        assert_eq!(normalize_id("5AB380779000").unwrap(), "5ab380779000");
+        assert_eq!(
+            normalize_id("3ae7deb6b0f4b1e7d2d3a04c8f4c4e5e9a5f3b21").unwrap(),
+            "3ae7deb6b0f4b1e7d2d3a04c8f4c4e5e9a5f3b21"
+        );
+        // The UUID form of a debug identifier, with and without an age
+        assert_eq!(
+            normalize_id("dfb8e43a-f242-3d73-a453-aeb6a777ef75").unwrap(),
+            "dfb8e43af2423d73a453aeb6a777ef750"
+        );
+        assert_eq!(
+            normalize_id("DFB8E43A-F242-3D73-A453-AEB6A777EF75-a").unwrap(),
+            "dfb8e43af2423d73a453aeb6a777ef75a"
+        );
+    }
+
+    #[test]
+    fn parses_options_around_fetch() {
+        let parse = |args: &str| {
+            Args::try_parse_from(std::iter::once("syms").chain(args.split_whitespace()))
+        };
+
+        for args in [
+            "--gcs-endpoint localhost:1 fetch -o /tmp/x abcd --bucket b --path p",
+            "fetch --gcs-endpoint localhost:1 -o /tmp/x abcd --bucket b --path p",
+            "--bucket b --path p --gcs-endpoint localhost:1 fetch -o /tmp/x abcd",
+        ] {
+            let args = parse(args).unwrap();
+            assert!(args.gcs.gcs_endpoint.is_some());
+            assert_eq!(
+                args.location.resolve().unwrap(),
+                ("b".to_owned(), "p".to_owned())
+            );
+
+            let Some(Command::Fetch(fetch)) = args.cmd else {
+                panic!("expected fetch");
+            };
+            assert_eq!(fetch.id, "abcd");
+            assert_eq!(fetch.output, std::path::Path::new("/tmp/x"));
+        }
+
+        // Upload arguments are rejected both before and after the subcommand
+        for args in [
+            "-c 3 fetch -o /tmp/x abcd",
+            "--strict fetch -o /tmp/x abcd",
+            "--bucket b --no-clobber fetch -o /tmp/x abcd",
+            "--resumable-threshold 1MiB fetch -o /tmp/x abcd",
+            "fetch -o /tmp/x abcd --compression-level 3",
+            "fetch -o /tmp/x abcd dir",
+        ] {
+            assert!(parse(args).is_err(), "{args}");
+        }
+
+        let args = parse("fetch -o /tmp/x abcd").unwrap();
+        assert_eq!(args.compression_level, DEFAULT_COMPRESSION_LEVEL);
+        assert_eq!(args.resumable_threshold, 8 * 1024 * 1024);
+
+        let args = parse("--bucket b --path p -c 3 dir fetch").unwrap();
+        assert!(args.cmd.is_none());
+        assert_eq!(args.compression_level, 3);
+        assert_eq!(args.dirs, [PathBuf::from("dir"), PathBuf::from("fetch")]);
+    }
+
+    #[test]
+    fn rejects_invalid_ids() {
+        assert!(normalize_id("").is_err());
+        assert!(normalize_id("ab").is_err());
+        assert!(normalize_id("not-an-id").is_err());
+        assert!(normalize_id("dfb8e43af2423d73a453aeb6a777ef75g").is_err());
+        assert!(normalize_id("../../etc/passwd").is_err());
+    }
+}